@@ -1,44 +1,101 @@
 use anyhow::Result;
 use clap::Parser;
+use std::path::PathBuf;
+use std::process::ExitCode;
 use tracing::error;
 use walrusfox::client;
 use walrusfox::config::Config;
-use walrusfox::installer;
+use walrusfox::doctor::Doctor;
+use walrusfox::installer::Installer;
+use walrusfox::profiles;
+use walrusfox::protocol::socket::ThemeMode;
 use walrusfox::server;
-use walrusfox::utils::cli::{Cli, Commands};
+use walrusfox::utils::cli::{Cli, Commands, ProfilesCommand};
 use walrusfox::utils::logging::init_logging;
 
-fn main() {
+fn main() -> ExitCode {
     let config = Config::new();
     let _guard = init_logging(&config);
 
     match Cli::try_parse() {
-        Ok(cli) => {
-            if let Err(e) = run(cli, config) {
+        Ok(cli) => match run(cli, config) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
                 error!("Error: {e}");
                 eprintln!("Error: {e}");
-                std::process::exit(1);
+                ExitCode::FAILURE
             }
-        }
+        },
         Err(e) => {
             eprintln!("{e}");
-            std::process::exit(1);
+            ExitCode::FAILURE
         }
     }
 }
 
 fn run(cli: Cli, config: Config) -> Result<()> {
     match cli.command {
-        Commands::Install => installer::Installer::new().install()?,
-        Commands::Uninstall => installer::Installer::new().uninstall()?,
-        Commands::PrintManifest => installer::Installer::new().print_manifest()?,
-        Commands::Start => server::Server::new(&config).init()?,
-        Commands::Update => client::Client::new(&config).update()?,
-        Commands::Dark => client::Client::new(&config).handle_dark()?,
-        Commands::Light => client::Client::new(&config).handle_light()?,
-        Commands::Auto => client::Client::new(&config).handle_auto()?,
+        Commands::Install {
+            system,
+            prefix,
+            copy,
+            systemd,
+        } => {
+            let installer = installer_for(system, prefix, copy)?;
+            installer.install()?;
+            if systemd {
+                installer.install_systemd(&config.socket_file)?;
+            }
+        }
+        Commands::Uninstall {
+            system,
+            prefix,
+            restore,
+        } => {
+            let installer = installer_for(system, prefix, false)?;
+            installer.uninstall()?;
+            if restore {
+                installer.restore_python()?;
+            }
+        }
+        Commands::Migrate => Installer::new()?.migrate()?,
+        Commands::Profiles {
+            command: ProfilesCommand::List { json },
+        } => profiles::print_list(Installer::new()?.home(), json)?,
+        Commands::PrintManifest => Installer::new()?.print_manifest()?,
+        Commands::Start => {
+            let shutdown = server::Shutdown::new();
+            shutdown.on_signals()?;
+            server::Server::new(&config)
+                .with_shutdown(shutdown)
+                .init()?
+        }
+        Commands::Update { palette, target } => {
+            client::Client::new(&config).update(target, palette.as_deref())?
+        }
+        Commands::Dark { target } => {
+            client::Client::new(&config).set_mode(ThemeMode::Dark, target)?
+        }
+        Commands::Light { target } => {
+            client::Client::new(&config).set_mode(ThemeMode::Light, target)?
+        }
+        Commands::Auto { target } => {
+            client::Client::new(&config).set_mode(ThemeMode::Auto, target)?
+        }
+        Commands::Events => client::Client::new(&config).events()?,
+        Commands::Status { json } => client::Client::new(&config).print_status(json)?,
+        Commands::Clients { json } => client::Client::new(&config).clients(json)?,
         Commands::Health => client::Client::new(&config).health()?,
         Commands::Diagnose => client::Client::new(&config).diagnose()?,
+        Commands::Doctor => Doctor::new()?.run()?,
     }
     Ok(())
 }
+
+fn installer_for(system: bool, prefix: Option<PathBuf>, copy: bool) -> Result<Installer> {
+    if system {
+        Installer::system(prefix)
+    } else {
+        Installer::user(copy)
+    }
+}
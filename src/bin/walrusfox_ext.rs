@@ -1,42 +1,105 @@
+use anyhow::{bail, Result};
 use std::env;
 use std::ffi::OsString;
 use std::os::unix::net::UnixStream;
+use std::process::ExitCode;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use tracing::{error, info, warn};
 use walrusfox::bridge::Bridge;
 use walrusfox::config::{Config, ALLOWED_EXTENSION};
-use walrusfox::server::Server;
+use walrusfox::server::{spawn_detached, InstanceLock, Server, Shutdown, SERVE_ARG};
 use walrusfox::utils::logging::init_logging;
 
-fn main() {
+/// Errors are returned as the exit code rather than through `process::exit`, so the logging
+/// guard is dropped and flushes the final lines.
+fn main() -> ExitCode {
     let config = Config::new();
     let _guard = init_logging(&config);
 
-    validate_args();
-    maybe_spawn_server(&config);
+    if env::args_os().nth(1).is_some_and(|arg| arg == SERVE_ARG) {
+        return serve(&config);
+    }
+    if !validate_args() {
+        return ExitCode::FAILURE;
+    }
+    let server = maybe_spawn_server(&config);
+
+    let result = Bridge::new(&config).run();
+    // Stop the embedded server cleanly so its clients are told and the socket is removed.
+    if let Some((shutdown, handle)) = server {
+        shutdown.trigger();
+        let _ = handle.join();
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("Host error: {e}");
+            eprintln!("Host error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    if let Err(e) = Bridge::new(&config).run() {
-        error!("Host error: {e}");
-        eprintln!("Host error: {e}");
-        std::process::exit(1);
+/// The detached server started by `spawn_detached`; the same as `walrusfox start`.
+fn serve(config: &Config) -> ExitCode {
+    let shutdown = Shutdown::new();
+    let result = shutdown
+        .on_signals()
+        .and_then(|()| Server::new(config).with_shutdown(shutdown).init());
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("Server error: {e}");
+            ExitCode::FAILURE
+        }
     }
 }
 
-fn maybe_spawn_server(config: &Config) {
-    if UnixStream::connect(&config.socket_file).is_ok() {
-        return; // server already up
+/// Makes sure a server is listening: preferably a detached `walrusfox-ext --serve` that outlives
+/// this host, otherwise one embedded in this process, which is returned so it can be stopped.
+fn maybe_spawn_server(config: &Config) -> Option<(Shutdown, JoinHandle<()>)> {
+    if !config.start_server || UnixStream::connect(&config.socket_file).is_ok() {
+        return None; // disabled or server already up
+    }
+    if InstanceLock::is_held(&config.socket_file) {
+        return None; // another host is starting one; the bridge keeps retrying until it listens
+    }
+    match start_detached_server(config) {
+        Ok(()) => return None,
+        Err(e) => warn!("Cannot start a detached server ({e:#}); embedding one instead"),
     }
 
     let config = config.clone();
-    let _ = std::thread::Builder::new()
+    let shutdown = Shutdown::new();
+    let server_shutdown = shutdown.clone();
+    let handle = std::thread::Builder::new()
         .name("walrusfox-embedded-server".to_string())
         .spawn(move || {
-            if let Err(e) = Server::new(&config).init() {
+            let server = Server::new(&config).with_shutdown(server_shutdown);
+            if let Err(e) = server.init() {
                 warn!("Embedded server failed to start: {}", e);
             }
-        });
+        })
+        .ok()?;
+    Some((shutdown, handle))
+}
+
+/// Spawns the detached server and waits until it listens.
+fn start_detached_server(config: &Config) -> Result<()> {
+    let pid = spawn_detached()?;
+    for _ in 0..30 {
+        if UnixStream::connect(&config.socket_file).is_ok() {
+            info!("Started detached server (pid {})", pid);
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    bail!("the server did not start listening within 3 seconds")
 }
 
-fn validate_args() {
+/// Whether the caller may use this host.
+fn validate_args() -> bool {
     let argv: Vec<OsString> = env::args_os().collect();
     info!("Called with : {:?}", argv);
     // Firefox passes [manifest_path, extension_id]
@@ -44,7 +107,8 @@ fn validate_args() {
         let caller = argv[2].to_string_lossy().to_string();
         if caller != ALLOWED_EXTENSION {
             warn!("Blocked origin: {}", caller);
-            std::process::exit(1);
+            return false;
         }
     }
+    true
 }
@@ -1,52 +1,136 @@
-use crate::config::Config;
-use crate::protocol::events::{BrowserAction, SocketCommand};
+use crate::config::{Config, RECONNECT_BASE, RECONNECT_MAX};
+use crate::profiles;
+use crate::protocol::events::BrowserAction;
 use crate::protocol::native_messaging::{
-    read_message, send_colors, send_invalid_response, send_theme_mode, send_version, Request,
+    read_message, send_colors, send_invalid_response, send_palette, send_theme_mode, send_version,
+    Request,
 };
+use crate::protocol::socket::{Command, Envelope, Hello, MessageType, Reply, Role, ThemeMode};
+use crate::server::{spawn_detached, InstanceLock};
+use crate::utils::backoff::Backoff;
 use anyhow::{Context, Result};
-use std::io::BufRead;
+use directories::BaseDirs;
+use parking_lot::Mutex;
+use std::fs;
 use std::io::BufReader;
+use std::io::{BufRead, Write};
 use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
 
 pub struct Bridge<'a> {
     config: &'a Config,
 }
 
+/// The browser (and profile) this host serves; matched against an envelope's `target`.
+#[derive(Debug, Clone)]
+pub struct HostInfo {
+    pub browser: String,
+    pub profile: Option<String>,
+}
+
+impl HostInfo {
+    /// Firefox is our parent process; its `lock` symlink tells which profile we serve.
+    pub fn detect() -> Self {
+        let parent = std::os::unix::process::parent_id();
+        let comm = fs::read_to_string(format!("/proc/{}/comm", parent)).unwrap_or_default();
+        let comm = comm.trim();
+        let mut info = Self {
+            browser: comm.strip_suffix("-bin").unwrap_or(comm).to_string(),
+            profile: None,
+        };
+        let home = BaseDirs::new().map(|b| b.home_dir().to_path_buf());
+        match home.and_then(|home| profiles::running_profile(&home, parent)) {
+            Some((browser, profile)) => {
+                info!(
+                    "Serving {} profile {} ({})",
+                    browser,
+                    profile.name,
+                    profile.path.display()
+                );
+                if info.browser.is_empty() {
+                    info.browser = browser.to_string();
+                }
+                info.profile = Some(profile.name);
+            }
+            None => debug!("No profile lock found for parent process {}", parent),
+        }
+        info
+    }
+
+    pub fn hello(&self) -> Hello {
+        Hello {
+            role: Role::Host,
+            browser: Some(self.browser.clone()),
+            profile: self.profile.clone(),
+            subscribe: false,
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match &self.profile {
+            Some(profile) => format!("{} ({})", self.browser, profile),
+            None => self.browser.clone(),
+        }
+    }
+
+    pub fn matches(&self, target: Option<&str>) -> bool {
+        match target {
+            None => true,
+            Some(t) => t == self.browser || Some(t) == self.profile.as_deref(),
+        }
+    }
+}
+
 impl<'a> Bridge<'a> {
     pub fn new(config: &'a Config) -> Self {
         Self { config }
     }
 
     pub fn run(&self) -> Result<()> {
+        let host = HostInfo::detect();
+
         let shutdown = Arc::new(AtomicBool::new(false));
         let shutdown_socket = shutdown.clone();
         let socket = self.config.socket_file.clone();
+        let colors_file = self.config.colors_file.clone();
+        let start_server = self.config.start_server;
+        // The mode last relayed to this host, including the state the server replays on
+        // connect; the browser's `theme:mode` requests are answered from it.
+        let mode = Arc::new(Mutex::new(ThemeMode::default()));
+        let socket_mode = mode.clone();
 
         let _ = thread::Builder::new()
             .name("walrusfox-bridge-socket".to_string())
             .spawn(move || {
                 let ss = shutdown_socket;
-                if let Err(e) = Self::socket_loop(ss.clone(), &socket) {
+                let result = Self::socket_loop(
+                    ss.clone(),
+                    &socket,
+                    &colors_file,
+                    &host,
+                    &socket_mode,
+                    start_server,
+                );
+                if let Err(e) = result {
                     if !ss.load(Ordering::SeqCst) {
                         error!("Socket loop failed: {e}");
                     }
                 }
             });
 
-        Self::native_messaging_loop()?;
+        Self::native_messaging_loop(self.config, &mode)?;
 
         warn!("Stdin closed; initiating graceful shutdown");
         shutdown.store(true, Ordering::SeqCst);
         Ok(())
     }
 
-    fn native_messaging_loop() -> Result<()> {
+    fn native_messaging_loop(config: &Config, mode: &Mutex<ThemeMode>) -> Result<()> {
         while let Some(msg) = read_message::<Request>()? {
             match msg.action.parse::<BrowserAction>() {
                 Ok(action) => {
@@ -55,7 +139,7 @@ impl<'a> Bridge<'a> {
                         send_invalid_response()?;
                         continue;
                     }
-                    Self::handle_browser_request(action)?;
+                    Self::handle_browser_request(action, config, mode)?;
                 }
                 Err(_) => {
                     warn!("Failed to parse browser action: {}", msg.action);
@@ -67,53 +151,129 @@ impl<'a> Bridge<'a> {
         Ok(())
     }
 
-    fn socket_loop(shutdown: Arc<AtomicBool>, path: &PathBuf) -> Result<()> {
-        loop {
-            if shutdown.load(Ordering::SeqCst) {
-                break;
-            }
-
+    /// Keeps a connection to the server. When it is lost, the bridge starts a replacement server
+    /// unless one holds the instance lock, and retries with exponential backoff and jitter. Each
+    /// outage is logged once when it starts and once when it ends.
+    fn socket_loop(
+        shutdown: Arc<AtomicBool>,
+        path: &PathBuf,
+        colors_file: &Path,
+        host: &HostInfo,
+        mode: &Mutex<ThemeMode>,
+        start_server: bool,
+    ) -> Result<()> {
+        let mut backoff = Backoff::new(RECONNECT_BASE, RECONNECT_MAX);
+        let mut outage: Option<Instant> = None;
+        while !shutdown.load(Ordering::SeqCst) {
             match UnixStream::connect(path) {
                 Ok(stream) => {
-                    info!("Connected to server at {}", path.display());
-                    if let Err(e) = Self::handle_command(stream) {
-                        if shutdown.load(Ordering::SeqCst) {
-                            break;
-                        }
-                        warn!("Socket handler ended: {e}");
+                    match outage.take() {
+                        Some(since) => info!(
+                            "Reconnected to server at {} after {:.1}s ({} attempt(s))",
+                            path.display(),
+                            since.elapsed().as_secs_f64(),
+                            backoff.attempts()
+                        ),
+                        None => info!("Connected to server at {}", path.display()),
                     }
-                }
-                Err(e) => {
+                    backoff.reset();
+                    let ended = Self::handle_command(stream, colors_file, host, mode);
                     if shutdown.load(Ordering::SeqCst) {
                         break;
                     }
-                    warn!("Cannot connect to {}: {e} (will retry)", path.display());
+                    match ended {
+                        Ok(()) => warn!("Server closed the connection; reconnecting"),
+                        Err(e) => warn!("Socket handler ended: {e:#}; reconnecting"),
+                    }
+                    outage = Some(Instant::now());
+                }
+                Err(e) => {
+                    if outage.is_none() {
+                        warn!(
+                            "Cannot connect to {}: {e}; retrying with backoff",
+                            path.display()
+                        );
+                        outage = Some(Instant::now());
+                    } else {
+                        debug!("Still cannot connect to {}: {e}", path.display());
+                    }
+                    if start_server {
+                        Self::replace_server(path, backoff.attempts() == 0);
+                    }
                 }
             }
 
-            for _ in 0..10 {
+            let resume = Instant::now() + backoff.next_delay();
+            while Instant::now() < resume {
                 if shutdown.load(Ordering::SeqCst) {
                     return Ok(());
                 }
-                thread::sleep(Duration::from_millis(100));
+                thread::sleep(Duration::from_millis(100).min(resume - Instant::now()));
             }
         }
         Ok(())
     }
 
-    fn handle_command(stream: UnixStream) -> Result<()> {
+    /// Starts a detached server unless another one holds the instance lock; of several bridges
+    /// racing here, the lock lets a single new server win.
+    fn replace_server(path: &Path, first_attempt: bool) {
+        if InstanceLock::is_held(path) {
+            return;
+        }
+        match spawn_detached() {
+            Ok(pid) => info!("Started a replacement server (pid {})", pid),
+            Err(e) if first_attempt => warn!("Cannot start a replacement server: {e:#}"),
+            Err(e) => debug!("Cannot start a replacement server: {e:#}"),
+        }
+    }
+
+    fn handle_command(
+        stream: UnixStream,
+        colors_file: &Path,
+        host: &HostInfo,
+        mode: &Mutex<ThemeMode>,
+    ) -> Result<()> {
+        writeln!(&stream, "{}", Envelope::hello(&host.hello()).to_line())
+            .context("Registering with the server")?;
         let reader = BufReader::new(&stream);
         for line in reader.lines() {
             debug!("Received line: {:?}", line);
             match line {
                 Ok(cmd) => {
+                    let envelope = match Envelope::parse_line(&cmd) {
+                        Ok(envelope) => envelope,
+                        Err(e) => {
+                            // Garbage from the socket is not the browser's business.
+                            warn!("Ignoring invalid socket message {:?}: {e:#}", cmd);
+                            continue;
+                        }
+                    };
+                    if envelope.kind == MessageType::Ping {
+                        writeln!(&stream, "{}", envelope.pong().to_line())
+                            .context("Answering heartbeat")?;
+                        continue;
+                    }
+                    if envelope.kind == MessageType::Goodbye {
+                        info!("Server is shutting down: {}", envelope.payload["reason"]);
+                        continue;
+                    }
+                    if envelope.kind != MessageType::Command {
+                        continue;
+                    }
                     info!("Received command: {}", cmd);
-                    match cmd.parse::<SocketCommand>() {
-                        Ok(SocketCommand::Update) => send_colors()?,
-                        Ok(SocketCommand::Auto) => send_theme_mode(SocketCommand::Auto.value())?,
-                        Ok(SocketCommand::Dark) => send_theme_mode(SocketCommand::Dark.value())?,
-                        Ok(SocketCommand::Light) => send_theme_mode(SocketCommand::Light.value())?,
-                        Ok(SocketCommand::Unknown(_)) | Err(_) => send_invalid_response()?,
+                    // The reply is written only after the frame reached the browser's stdin.
+                    let reply = if !host.matches(envelope.target.as_deref()) {
+                        Reply::skipped()
+                    } else {
+                        match envelope.as_command() {
+                            Ok(command) => Self::apply_command(command, colors_file, mode)?,
+                            Err(e) => Reply::error(format!("{e:#}")),
+                        }
+                    };
+                    let reply = reply.with_host(host.label());
+                    if envelope.id.is_some() {
+                        let line = envelope.reply(&reply).to_line();
+                        writeln!(&stream, "{}", line).context("Writing result to socket")?;
                     }
                 }
                 Err(e) => {
@@ -125,13 +285,55 @@ impl<'a> Bridge<'a> {
         Ok(())
     }
 
-    fn handle_browser_request(action: BrowserAction) -> Result<()> {
+    /// Forwards a socket command to the browser; I/O errors on stdout are fatal for the host.
+    fn apply_command(
+        command: Command,
+        colors_file: &Path,
+        current_mode: &Mutex<ThemeMode>,
+    ) -> Result<Reply> {
+        Ok(match command {
+            Command::Update { palette: None } => {
+                if send_colors(colors_file)? {
+                    Reply::ok(serde_json::Value::Null)
+                } else {
+                    Reply::error(format!(
+                        "failed to load colors from {}",
+                        colors_file.display()
+                    ))
+                }
+            }
+            Command::Update {
+                palette: Some(palette),
+            } => {
+                send_palette(palette.colors, palette.wallpaper)?;
+                Reply::ok(serde_json::Value::Null)
+            }
+            Command::Mode { mode } => {
+                send_theme_mode(mode.value())?;
+                *current_mode.lock() = mode;
+                Reply::ok(serde_json::Value::Null)
+            }
+            Command::Css { .. } => Reply::error("custom CSS is not supported by walrusfox"),
+        })
+    }
+
+    fn handle_browser_request(
+        action: BrowserAction,
+        config: &Config,
+        mode: &Mutex<ThemeMode>,
+    ) -> Result<()> {
         info!("Action received {:?}", action);
         match action {
             BrowserAction::Version => send_version()?,
-            BrowserAction::Colors => send_colors()?,
+            BrowserAction::Colors => {
+                send_colors(&config.colors_file)?;
+            }
             BrowserAction::Invalid => send_invalid_response()?,
-            BrowserAction::ThemeMode => send_theme_mode("auto")?,
+            BrowserAction::ThemeMode => {
+                // Never asks the server, so a stuck one cannot hold up the browser.
+                let mode = *mode.lock();
+                send_theme_mode(mode.value())?
+            }
         }
         Ok(())
     }
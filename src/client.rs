@@ -1,11 +1,16 @@
-use crate::config::Config;
-use crate::protocol::events::SocketCommand;
-use crate::utils::themes;
-use anyhow::Result;
+use crate::config::{Config, ACK_TIMEOUT};
+use crate::protocol::socket::{
+    ClientInfo, Command, Envelope, Hello, MessageType, Palette, Query, Reply, Role, ServerStatus,
+    ThemeMode,
+};
+use crate::utils::{themes, time};
+use anyhow::{Context, Result};
 use std::fs;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub struct Client<'a> {
     config: &'a Config,
@@ -16,33 +21,184 @@ impl<'a> Client<'a> {
         Self { config }
     }
 
-    pub fn update(&self) -> Result<()> {
-        self.send_command(SocketCommand::Update.value())
+    /// Re-themes from the configured colors file, or from `palette` sent inline.
+    pub fn update(&self, target: Option<String>, palette: Option<&Path>) -> Result<()> {
+        let palette = match palette {
+            Some(path) => {
+                let (colors, wallpaper) = themes::read_colors(path)?;
+                Some(Palette { colors, wallpaper })
+            }
+            None => None,
+        };
+        self.send_command(&Command::Update { palette }, target)
     }
 
-    pub fn handle_dark(&self) -> Result<()> {
-        self.send_command(SocketCommand::Dark.value())
+    pub fn set_mode(&self, mode: ThemeMode, target: Option<String>) -> Result<()> {
+        self.send_command(&Command::Mode { mode }, target)
     }
 
-    pub fn handle_light(&self) -> Result<()> {
-        self.send_command(SocketCommand::Light.value())
+    /// Subscribes as a controller and prints every event the server emits.
+    pub fn events(&self) -> Result<()> {
+        let stream = self.connect()?;
+        let hello = Hello {
+            subscribe: true,
+            ..Hello::default()
+        };
+        writeln!(&stream, "{}", Envelope::hello(&hello).to_line())?;
+        for line in BufReader::new(&stream).lines() {
+            let line = line.context("Reading events from the server")?;
+            match Envelope::parse_line(&line) {
+                Ok(env) if env.kind == MessageType::Event => println!("{}", env.payload),
+                // Subscribers are registered, so the server expects heartbeats answered.
+                Ok(env) if env.kind == MessageType::Ping => {
+                    writeln!(&stream, "{}", env.pong().to_line())?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The theme in effect and server counters.
+    pub fn status(&self) -> Result<ServerStatus> {
+        let reply = self.request(Envelope::query(&Query::Status))?;
+        if !reply.ok {
+            anyhow::bail!(reply.error.unwrap_or_else(|| "query failed".into()));
+        }
+        Ok(serde_json::from_value(reply.data)?)
+    }
+
+    pub fn print_status(&self, json: bool) -> Result<()> {
+        let status = self.status()?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&status)?);
+            return Ok(());
+        }
+        let theme = &status.theme;
+        println!("Mode: {}", theme.mode.value());
+        match &theme.palette {
+            Some(palette) => {
+                println!(
+                    "Palette: {} ({} colors, hash {})",
+                    palette.source,
+                    palette.colors,
+                    palette.hash.as_deref().unwrap_or("unavailable")
+                );
+                if let Some(wallpaper) = &palette.wallpaper {
+                    println!("Wallpaper: {}", wallpaper);
+                }
+            }
+            None => println!("Palette: none sent since the server started"),
+        }
+        match theme.updated_at {
+            Some(at) => println!("Last update: {}", time::ago(at)),
+            None => println!("Last update: never"),
+        }
+        println!(
+            "Server: walrusfox {} (pid {}), started {}",
+            status.version,
+            status.pid,
+            time::ago(status.started_at)
+        );
+        println!(
+            "Clients: {} browser host(s), {} other client(s)",
+            status.hosts, status.controllers
+        );
+        if status.evicted > 0 {
+            println!("Evicted: {} client(s) that stopped reading", status.evicted);
+        }
+        Ok(())
     }
 
-    pub fn handle_auto(&self) -> Result<()> {
-        self.send_command(SocketCommand::Auto.value())
+    /// Lists the other connections to the server (browser hosts first).
+    pub fn clients(&self, json: bool) -> Result<()> {
+        let clients = self.list_clients()?;
+        if json {
+            println!("{}", serde_json::to_string_pretty(&clients)?);
+            return Ok(());
+        }
+        if clients.is_empty() {
+            println!("No other clients connected");
+            return Ok(());
+        }
+        println!(
+            "{:<4} {:<10} {:<8} {:<6} {:<32} {:<10} {:<28} {:<9} ERRORS",
+            "ID", "ROLE", "PID", "UID", "BROWSER", "CONNECTED", "LAST COMMAND", "RTT"
+        );
+        for c in &clients {
+            let role = match c.role {
+                Role::Host => "host",
+                Role::Controller => "controller",
+            };
+            let last = match (&c.last_command, c.last_command_at) {
+                (Some(cmd), Some(at)) => format!("{} ({})", cmd, time::ago(at)),
+                _ => "-".into(),
+            };
+            let opt = |v: Option<u32>| v.map_or("-".into(), |v| v.to_string());
+            let browser = match c.role {
+                Role::Host => c.label(),
+                Role::Controller => "-".into(),
+            };
+            let rtt = c.rtt_ms.map_or("-".into(), |ms| format!("{:.1}ms", ms));
+            println!(
+                "{:<4} {:<10} {:<8} {:<6} {:<32} {:<10} {:<28} {:<9} {}",
+                c.id,
+                role,
+                opt(c.pid),
+                opt(c.uid),
+                browser,
+                time::ago(c.connected_at),
+                last,
+                rtt,
+                c.errors
+            );
+        }
+        Ok(())
     }
 
+    /// Pings the server, then reports each browser's latency as of its last heartbeat.
     pub fn health(&self) -> Result<()> {
-        let socket = self.config.socket_file.clone();
-        match UnixStream::connect(&socket) {
-            Ok(_) => {
-                println!("Server is reachable at {}", socket.display());
-                Ok(())
+        let stream = self.connect()?;
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        let id = request_id();
+        let sent = Instant::now();
+        writeln!(&stream, "{}", Envelope::ping(id.clone()).to_line())?;
+        let mut reader = BufReader::new(&stream);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = reader
+                .read_line(&mut line)
+                .context("Waiting for the server to answer a ping")?;
+            if n == 0 {
+                anyhow::bail!("Server closed the connection before answering a ping");
             }
-            Err(e) => {
-                anyhow::bail!("Cannot connect to server at {}: {}\nHint: run `walrusfox start` to launch the server.", socket.display(), e)
+            match Envelope::parse_line(&line) {
+                Ok(env) if env.kind == MessageType::Pong && env.id.as_deref() == Some(&id) => break,
+                _ => continue,
             }
         }
+        println!(
+            "Server is reachable at {} (round trip {:.1}ms)",
+            self.config.socket_file.display(),
+            sent.elapsed().as_secs_f64() * 1000.0
+        );
+
+        let hosts: Vec<ClientInfo> = self
+            .list_clients()?
+            .into_iter()
+            .filter(|c| c.role == Role::Host)
+            .collect();
+        if hosts.is_empty() {
+            println!("No browser connected");
+        }
+        for host in &hosts {
+            match host.rtt_ms {
+                Some(ms) => println!("{}: heartbeat round trip {:.1}ms", host.label(), ms),
+                None => println!("{}: no heartbeat answered yet", host.label()),
+            }
+        }
+        Ok(())
     }
 
     pub fn diagnose(&self) -> Result<()> {
@@ -95,7 +251,8 @@ impl<'a> Client<'a> {
         }
 
         // Colors
-        match themes::read_colors() {
+        println!("Colors file: {}", self.config.colors_file.display());
+        match themes::read_colors(&self.config.colors_file) {
             Ok((colors, wall)) => {
                 println!("Colors: OK ({} colors)", colors.len());
                 if let Some(w) = wall {
@@ -108,10 +265,10 @@ impl<'a> Client<'a> {
         Ok(())
     }
 
-    fn send_command(&self, cmd: &str) -> Result<()> {
+    pub(crate) fn connect(&self) -> Result<UnixStream> {
         let socket = self.config.socket_file.clone();
-        let mut stream = match UnixStream::connect(&socket) {
-            Ok(s) => s,
+        match UnixStream::connect(&socket) {
+            Ok(s) => Ok(s),
             Err(e) => {
                 anyhow::bail!(
                     "Cannot connect to server at {}: {}\nHint: run `walrusfox start` to launch the server.",
@@ -119,8 +276,70 @@ impl<'a> Client<'a> {
                     e
                 )
             }
-        };
-        writeln!(stream, "{}", cmd)?;
+        }
+    }
+
+    /// Other connections to the server, browser hosts first.
+    fn list_clients(&self) -> Result<Vec<ClientInfo>> {
+        let reply = self.request(Envelope::query(&Query::Clients))?;
+        if !reply.ok {
+            anyhow::bail!(reply.error.unwrap_or_else(|| "query failed".into()));
+        }
+        let mut clients: Vec<ClientInfo> = serde_json::from_value(reply.data)?;
+        clients.sort_by_key(|c| (c.role != Role::Host, c.id));
+        Ok(clients)
+    }
+
+    /// Sends `envelope` with a fresh id and waits for the matching `result`.
+    pub(crate) fn request(&self, envelope: Envelope) -> Result<Reply> {
+        let mut stream = self.connect()?;
+        let id = request_id();
+        writeln!(stream, "{}", envelope.with_id(id.clone()).to_line())?;
+
+        // The server answers once every browser confirmed or after ACK_TIMEOUT.
+        stream.set_read_timeout(Some(ACK_TIMEOUT + Duration::from_secs(2)))?;
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = reader
+                .read_line(&mut line)
+                .context("Waiting for the server to answer")?;
+            if n == 0 {
+                anyhow::bail!("Server closed the connection before answering");
+            }
+            match Envelope::parse_line(&line) {
+                Ok(env) if env.kind == MessageType::Result && env.id.as_deref() == Some(&id) => {
+                    return env.as_reply();
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    fn send_command(&self, command: &Command, target: Option<String>) -> Result<()> {
+        let result = self.request(Envelope::command(command).with_target(target))?;
+        for reply in result.results() {
+            let host = reply.host.as_deref().unwrap_or("unknown host");
+            if reply.skipped {
+                println!("{}: skipped (target does not match)", host);
+            } else if reply.ok {
+                println!("{}: ok", host);
+            } else {
+                println!("{}: {}", host, reply.error.as_deref().unwrap_or("failed"));
+            }
+        }
+        if !result.ok {
+            anyhow::bail!(result.error.unwrap_or_else(|| "command failed".into()));
+        }
         Ok(())
     }
 }
+
+fn request_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    format!("{}-{}", std::process::id(), nanos)
+}
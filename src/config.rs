@@ -1,28 +1,119 @@
+use anyhow::{Context, Result};
 use directories::ProjectDirs;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::env;
 use std::fs;
 use std::os::unix::fs::DirBuilderExt;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tracing::warn;
 
 pub const HOST_NAME: &str = "pywalfox"; // keep the same host name used by the Python implementation
 pub const ALLOWED_EXTENSION: &str = "pywalfox@frewacom.org"; // Firefox add-on id
 pub const MAX_MSG_LEN: usize = 64 * 1024; // 64 KiB
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(5); // how long the server waits for browsers to confirm a command
+pub const MAX_CLIENTS: usize = 128; // connections the server accepts at once
+pub const MAX_QUEUED_BYTES: usize = 1024 * 1024; // per-client outbound queue; 1 MiB
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(10); // how long a client may leave queued output unread
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2); // how long the server flushes queues on shutdown
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10); // how often the server pings its clients
+pub const MAX_MISSED_HEARTBEATS: u32 = 3; // unanswered pings before a client is disconnected
+pub const RECONNECT_BASE: Duration = Duration::from_millis(250); // first bridge reconnect delay
+pub const RECONNECT_MAX: Duration = Duration::from_secs(30); // cap for the doubling reconnect delay
+pub const DEFAULT_DEBOUNCE_MS: u64 = 200; // window for coalescing identical commands
+pub const DEFAULT_COMMANDS_PER_SECOND: u32 = 10; // per-client rate limit
 
 #[derive(Debug, Default, Deserialize, Clone)]
 pub struct Config {
     pub socket_file: PathBuf,
     pub log_file: PathBuf,
+    pub colors_file: PathBuf,
+    /// Where the server persists the theme mode and palette; `None` disables persistence.
+    pub state_file: Option<PathBuf>,
+    /// Whether the extension host may start a server; `WALRUSFOX_NO_SERVER` turns it off.
+    pub start_server: bool,
+    pub settings: Settings,
+}
+
+/// User settings persisted in `$XDG_CONFIG_HOME/walrusfox/config.json`; every field is optional.
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Settings {
+    pub colors_file: Option<PathBuf>,
+    /// Group (name or gid) whose members may use the socket besides our own user.
+    pub allowed_group: Option<String>,
+    /// Window in milliseconds within which identical commands are coalesced; 0 disables it.
+    pub debounce_ms: Option<u64>,
+    /// Commands a single client may send per second; 0 disables the limit.
+    pub max_commands_per_second: Option<u32>,
+    /// Loopback port for the HTTP/WebSocket gateway; unset keeps it off.
+    pub gateway_port: Option<u16>,
+    /// Own `org.walrusfox.Host` on the session bus.
+    pub dbus: bool,
+}
+
+impl Settings {
+    pub fn path() -> Option<PathBuf> {
+        if let Ok(p) = env::var("WALRUSFOX_CONFIG") {
+            return Some(PathBuf::from(p));
+        }
+        ProjectDirs::from("de", "linket", "walrusfox")
+            .map(|proj| proj.config_dir().join("config.json"))
+    }
+
+    /// Token the gateway requires, kept next to the config file.
+    pub fn gateway_token_path() -> Option<PathBuf> {
+        Some(Self::path()?.with_file_name("gateway-token"))
+    }
+
+    /// Missing or unreadable files yield the defaults, so a broken config never blocks the host.
+    pub fn load() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+        match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring invalid config {}: {}", path.display(), e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS))
+    }
+
+    pub fn commands_per_second(&self) -> u32 {
+        self.max_commands_per_second
+            .unwrap_or(DEFAULT_COMMANDS_PER_SECOND)
+    }
+
+    pub fn save(&self) -> Result<PathBuf> {
+        let path = Self::path().context("no config directory available")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(&path, data).with_context(|| format!("writing {}", path.display()))?;
+        Ok(path)
+    }
 }
 
 impl Config {
     pub fn new() -> Self {
+        let settings = Settings::load();
         let socket_file = Self::socket_file_path();
         let log_file = Self::log_file_path();
+        let colors_file = Self::colors_file_path(&settings);
+        let state_file = Self::state_file_path();
         Self {
             socket_file,
             log_file,
+            colors_file,
+            state_file,
+            start_server: env::var_os("WALRUSFOX_NO_SERVER").is_none(),
+            settings,
         }
     }
 
@@ -53,6 +144,31 @@ impl Config {
         PathBuf::from("/tmp/walrusfox.log")
     }
 
+    fn state_file_path() -> Option<PathBuf> {
+        if let Ok(p) = env::var("WALRUSFOX_STATE") {
+            return Some(PathBuf::from(p));
+        }
+        let proj = ProjectDirs::from("de", "linket", "walrusfox")?;
+        Some(proj.state_dir()?.join("state.json"))
+    }
+
+    fn colors_file_path(settings: &Settings) -> PathBuf {
+        if let Ok(p) = env::var("WALRUSFOX_COLORS") {
+            return PathBuf::from(p);
+        }
+        if let Some(p) = &settings.colors_file {
+            return p.clone();
+        }
+        Self::colors_default_path()
+    }
+
+    pub fn colors_default_path() -> PathBuf {
+        let home = directories::BaseDirs::new()
+            .map(|b| b.home_dir().to_path_buf())
+            .unwrap_or_else(|| PathBuf::from("/tmp"));
+        home.join(".cache").join("wal").join("walrusfox.json")
+    }
+
     fn ensure_dir_mode_0700(dir: &Path) {
         if dir.exists() {
             return;
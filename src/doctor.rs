@@ -0,0 +1,280 @@
+use crate::config::{ALLOWED_EXTENSION, HOST_NAME};
+use crate::installer::{legacy, sandbox, InstalledManifest, Installer};
+use crate::protocol::events::BrowserAction;
+use crate::protocol::native_messaging::{read_message_from, write_message_to};
+use anyhow::{Context, Result};
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const PROBE_EXIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+enum Outcome {
+    Pass,
+    Warn(String),
+    Fail(String),
+}
+
+struct Check {
+    name: String,
+    outcome: Outcome,
+}
+
+/// End-to-end verification of the browser integration: manifests, host binary and conflicts.
+pub struct Doctor {
+    installer: Installer,
+    checks: Vec<Check>,
+}
+
+impl Doctor {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            installer: Installer::new()?,
+            checks: Vec::new(),
+        })
+    }
+
+    pub fn run(mut self) -> Result<()> {
+        println!("### walrusfox doctor");
+
+        let mut installed = 0;
+        for (label, path) in self.installer.manifest_locations() {
+            if !path.exists() {
+                if label == sandbox::Packaging::Flatpak.label() {
+                    self.fail(
+                        format!("manifest ({label}) at {}", path.display()),
+                        "Flatpak Firefox is installed but has no manifest; run `walrusfox install`",
+                    );
+                }
+                continue;
+            }
+            installed += 1;
+            self.check_manifest(label, &path);
+        }
+        if installed == 0 {
+            self.fail(
+                "manifest installed",
+                "no pywalfox manifest found; run `walrusfox install`",
+            );
+        }
+
+        self.check_python_leftovers();
+        self.summary()
+    }
+
+    fn check_manifest(&mut self, label: &str, path: &Path) {
+        let name = format!("manifest ({label}) at {}", path.display());
+        let manifest = match InstalledManifest::read(path) {
+            Ok(m) => m,
+            Err(e) => {
+                self.fail(
+                    name,
+                    format!("{e:#}; re-run `walrusfox install` to rewrite it"),
+                );
+                return;
+            }
+        };
+
+        if legacy::is_python_host(&manifest.path) {
+            self.fail(
+                name,
+                format!(
+                    "points at the Python pywalfox host {}; run `walrusfox install` to replace it",
+                    manifest.path
+                ),
+            );
+            return;
+        }
+
+        let mut problems = Vec::new();
+        if manifest.name != HOST_NAME {
+            problems.push(format!(
+                "name is {:?}, expected {:?}",
+                manifest.name, HOST_NAME
+            ));
+        }
+        if manifest.r#type != "stdio" {
+            problems.push(format!("type is {:?}, expected \"stdio\"", manifest.r#type));
+        }
+        if !manifest
+            .allowed_extensions
+            .iter()
+            .any(|e| e == ALLOWED_EXTENSION)
+        {
+            problems.push(format!("allowed_extensions lacks {ALLOWED_EXTENSION}"));
+        }
+        let host = Path::new(&manifest.path);
+        if !host.is_absolute() {
+            problems.push(format!("path {} is not absolute", manifest.path));
+        } else if let Err(e) = Self::check_executable(host) {
+            problems.push(e);
+        }
+        if !problems.is_empty() {
+            self.fail(
+                name,
+                format!("{}; re-run `walrusfox install`", problems.join(", ")),
+            );
+            return;
+        }
+        self.pass(name);
+
+        // The Flatpak wrapper only works inside the sandbox, so probe the host it wraps instead.
+        if label == sandbox::Packaging::Flatpak.label() {
+            if !sandbox::flatpak_can_spawn_host(self.installer.home()) {
+                self.fail(
+                    "flatpak host access",
+                    format!(
+                        "Firefox may not run programs outside its sandbox; run `{}`",
+                        sandbox::flatpak_override_hint()
+                    ),
+                );
+            }
+            return;
+        }
+
+        let probe = format!("host answers debug:version ({})", manifest.path);
+        match Self::probe_host(path, host) {
+            Ok(version) if version == env!("CARGO_PKG_VERSION") => self.pass(probe),
+            Ok(version) => self.warn(
+                probe,
+                format!(
+                    "host reports version {version} but this CLI is {}; reinstall to update it",
+                    env!("CARGO_PKG_VERSION")
+                ),
+            ),
+            Err(e) => self.fail(
+                probe,
+                format!("{e:#}; check the log file and run `walrusfox install` again"),
+            ),
+        }
+    }
+
+    fn check_python_leftovers(&mut self) {
+        let executables = legacy::python_executables();
+        if executables.is_empty() {
+            self.pass("no Python pywalfox install on PATH");
+            return;
+        }
+        let list: Vec<String> = executables
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        self.warn(
+            "no Python pywalfox install on PATH",
+            format!(
+                "found {}; `pywalfox install` would overwrite our manifest, remove it with `pip uninstall pywalfox`",
+                list.join(", ")
+            ),
+        );
+    }
+
+    fn check_executable(host: &Path) -> Result<(), String> {
+        match host.metadata() {
+            Ok(meta) if meta.is_file() && meta.permissions().mode() & 0o111 != 0 => Ok(()),
+            Ok(_) => Err(format!("{} is not an executable file", host.display())),
+            Err(e) => Err(format!("{}: {e}", host.display())),
+        }
+    }
+
+    /// Launches the host like Firefox does and returns the version it reports. The host is
+    /// pointed at a socket nobody listens on and told not to start a server, so the probe
+    /// neither registers as a browser with the running server nor leaves one behind.
+    fn probe_host(manifest: &Path, host: &Path) -> Result<String> {
+        let scratch = env::temp_dir().join(format!("walrusfox-doctor-{}", process::id()));
+        fs::create_dir_all(&scratch).with_context(|| format!("creating {}", scratch.display()))?;
+        let result = Self::run_probe(manifest, host, &scratch.join("probe.sock"));
+        let _ = fs::remove_dir_all(&scratch);
+        result
+    }
+
+    fn run_probe(manifest: &Path, host: &Path, socket: &Path) -> Result<String> {
+        let mut child = Command::new(host)
+            .arg(manifest)
+            .arg(ALLOWED_EXTENSION)
+            .env("WALRUSFOX_SOCKET", socket)
+            .env("WALRUSFOX_NO_SERVER", "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .with_context(|| format!("starting {}", host.display()))?;
+
+        let request = serde_json::json!({ "action": BrowserAction::Version.value() });
+        {
+            // Closing stdin afterwards makes the host exit once it has answered.
+            let mut stdin = child.stdin.take().context("host stdin")?;
+            write_message_to(&mut stdin, &request)?;
+        }
+
+        let mut stdout = child.stdout.take().context("host stdout")?;
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(read_message_from::<serde_json::Value, _>(&mut stdout));
+        });
+        let reply = rx.recv_timeout(PROBE_TIMEOUT);
+        // Stdin is closed, so the host should be on its way out; kill it only if it lingers.
+        let deadline = Instant::now() + PROBE_EXIT_TIMEOUT;
+        while matches!(child.try_wait(), Ok(None)) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        if matches!(child.try_wait(), Ok(None)) {
+            let _ = child.kill();
+        }
+        let _ = child.wait();
+
+        let reply = match reply {
+            Ok(reply) => reply?.context("host closed stdout without answering")?,
+            Err(_) => anyhow::bail!("no answer within {}s", PROBE_TIMEOUT.as_secs()),
+        };
+        if reply["action"] != BrowserAction::Version.value() || reply["success"] != true {
+            anyhow::bail!("unexpected reply {reply}");
+        }
+        reply["data"]
+            .as_str()
+            .map(str::to_string)
+            .context("reply carries no version")
+    }
+
+    fn pass(&mut self, name: impl Into<String>) {
+        self.record(name, Outcome::Pass);
+    }
+
+    fn warn(&mut self, name: impl Into<String>, fix: impl Into<String>) {
+        self.record(name, Outcome::Warn(fix.into()));
+    }
+
+    fn fail(&mut self, name: impl Into<String>, fix: impl Into<String>) {
+        self.record(name, Outcome::Fail(fix.into()));
+    }
+
+    fn record(&mut self, name: impl Into<String>, outcome: Outcome) {
+        let check = Check {
+            name: name.into(),
+            outcome,
+        };
+        match &check.outcome {
+            Outcome::Pass => println!("[PASS] {}", check.name),
+            Outcome::Warn(fix) => println!("[WARN] {}\n       fix: {}", check.name, fix),
+            Outcome::Fail(fix) => println!("[FAIL] {}\n       fix: {}", check.name, fix),
+        }
+        self.checks.push(check);
+    }
+
+    fn summary(&self) -> Result<()> {
+        let count = |f: fn(&Outcome) -> bool| self.checks.iter().filter(|c| f(&c.outcome)).count();
+        let passed = count(|o| matches!(o, Outcome::Pass));
+        let warned = count(|o| matches!(o, Outcome::Warn(_)));
+        let failed = count(|o| matches!(o, Outcome::Fail(_)));
+        println!("Summary: {passed} passed, {warned} warnings, {failed} failed");
+        if failed > 0 {
+            anyhow::bail!("doctor found {failed} problem(s)");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use crate::profiles;
+use anyhow::{Context, Result};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+const PYTHON_CLI: &str = "pywalfox";
+const BACKUP_RECORD: &str = "backup.json";
+
+/// True when a manifest `path` points at the Python host: a Python script, or a shell script
+/// starting pywalfox. The file's contents decide; a missing one only by its path.
+pub fn is_python_host(host_path: &str) -> bool {
+    let Ok(data) = fs::read(host_path) else {
+        return host_path.contains("pywalfox");
+    };
+    let Some(script) = data.strip_prefix(b"#!") else {
+        return false; // a binary, e.g. walrusfox-ext
+    };
+    let script = String::from_utf8_lossy(script);
+    let interpreter = script.lines().next().unwrap_or_default();
+    interpreter.contains("python") || (script.contains("pywalfox") && !script.contains("walrusfox"))
+}
+
+/// `pywalfox` executables on `PATH` left behind by `pip install pywalfox`.
+pub fn python_executables() -> Vec<PathBuf> {
+    let Some(path) = env::var_os("PATH") else {
+        return Vec::new();
+    };
+    env::split_paths(&path)
+        .map(|dir| dir.join(PYTHON_CLI))
+        .filter(|p| {
+            p.metadata()
+                .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackedUpFile {
+    pub original: PathBuf,
+    pub backup: PathBuf,
+}
+
+/// What `install` saved of a Python pywalfox setup before overwriting its manifest.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PythonBackup {
+    pub manifest: BackedUpFile,
+    pub host_path: PathBuf,
+    pub scripts: Vec<BackedUpFile>,
+}
+
+impl PythonBackup {
+    fn dir() -> Result<PathBuf> {
+        let proj = ProjectDirs::from("de", "linket", "walrusfox")
+            .context("no data directory available")?;
+        Ok(proj.data_dir().join("pywalfox-backup"))
+    }
+
+    pub fn load() -> Result<Option<Self>> {
+        let record = Self::dir()?.join(BACKUP_RECORD);
+        if !record.exists() {
+            return Ok(None);
+        }
+        let data = fs::read(&record).with_context(|| format!("reading {}", record.display()))?;
+        let backup = serde_json::from_slice(&data)
+            .with_context(|| format!("parsing {}", record.display()))?;
+        Ok(Some(backup))
+    }
+
+    /// Copies the Python manifest and the scripts next to its host; `None` if it is not Python's.
+    ///
+    /// An existing backup is kept, so re-running `install` never replaces the original files.
+    pub fn create(manifest_path: &Path) -> Result<Option<Self>> {
+        let Ok(installed) = super::InstalledManifest::read(manifest_path) else {
+            return Ok(None);
+        };
+        if !is_python_host(&installed.path) {
+            return Ok(None);
+        }
+        if let Some(existing) = Self::load()? {
+            return Ok(Some(existing));
+        }
+
+        let dir = Self::dir()?;
+        fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        let manifest = Self::copy(manifest_path, &dir)?;
+
+        let host_path = PathBuf::from(&installed.path);
+        let mut scripts = Vec::new();
+        if let Some(script_dir) = host_path.parent() {
+            let target = dir.join("scripts");
+            fs::create_dir_all(&target)
+                .with_context(|| format!("creating {}", target.display()))?;
+            for entry in fs::read_dir(script_dir).into_iter().flatten().flatten() {
+                if entry.path().is_file() {
+                    scripts.push(Self::copy(&entry.path(), &target)?);
+                }
+            }
+        }
+
+        let backup = Self {
+            manifest,
+            host_path,
+            scripts,
+        };
+        let record = dir.join(BACKUP_RECORD);
+        fs::write(&record, serde_json::to_vec_pretty(&backup)?)
+            .with_context(|| format!("writing {}", record.display()))?;
+        Ok(Some(backup))
+    }
+
+    /// Puts the Python manifest (and any script that has gone missing) back in place.
+    pub fn restore(&self) -> Result<()> {
+        Self::put_back(&self.manifest)?;
+        for script in &self.scripts {
+            if !script.original.exists() {
+                Self::put_back(script)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy(original: &Path, dir: &Path) -> Result<BackedUpFile> {
+        let name = original
+            .file_name()
+            .context("backup source has no file name")?;
+        let backup = dir.join(name);
+        fs::copy(original, &backup).with_context(|| {
+            format!("backing up {} to {}", original.display(), backup.display())
+        })?;
+        Ok(BackedUpFile {
+            original: original.to_path_buf(),
+            backup,
+        })
+    }
+
+    fn put_back(file: &BackedUpFile) -> Result<()> {
+        if let Some(dir) = file.original.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        fs::copy(&file.backup, &file.original).with_context(|| {
+            format!(
+                "restoring {} to {}",
+                file.backup.display(),
+                file.original.display()
+            )
+        })?;
+        println!("Restored {}", file.original.display());
+        Ok(())
+    }
+}
+
+/// pywal's own palette, which the Python host read instead of `walrusfox.json`.
+pub fn python_colors_file(home: &Path) -> PathBuf {
+    home.join(".cache").join("wal").join("colors.json")
+}
+
+/// Stylesheets in the profiles' `chrome` dirs that the Python host's custom CSS wrote to.
+pub fn custom_css_files(home: &Path) -> Vec<PathBuf> {
+    profiles::discover(home)
+        .into_iter()
+        .flat_map(|b| b.profiles)
+        .flat_map(|profile| {
+            let chrome = profile.path.join("chrome");
+            [
+                chrome.join("userChrome.css"),
+                chrome.join("userContent.css"),
+            ]
+        })
+        .filter(|css| mentions_pywalfox(css))
+        .collect()
+}
+
+fn mentions_pywalfox(css: &Path) -> bool {
+    fs::read_to_string(css)
+        .map(|data| data.to_lowercase().contains("pywalfox"))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_python_hosts_by_contents() {
+        let dir = env::temp_dir().join(format!("walrusfox-legacy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let host = |name: &str, contents: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            path.display().to_string()
+        };
+
+        assert!(is_python_host(&host(
+            "main.sh",
+            b"#!/bin/bash\npython3 -m pywalfox start\n"
+        )));
+        assert!(is_python_host(&host(
+            "host",
+            b"#!/usr/bin/env python3\nimport sys\n"
+        )));
+        // Names no longer matter: a wrapper script for walrusfox is not Python's.
+        assert!(!is_python_host(&host(
+            "walrusfox-ext.sh",
+            b"#!/bin/sh\nexec flatpak-spawn --host walrusfox-ext \"$@\"\n"
+        )));
+        assert!(!is_python_host(&host("helper.py", b"\x7fELF\x02\x01")));
+        assert!(is_python_host(
+            "/home/u/.local/lib/python3.12/site-packages/pywalfox/bin/main.sh"
+        ));
+        assert!(!is_python_host("/missing/walrusfox/walrusfox-ext"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,550 @@
+pub mod legacy;
+pub mod record;
+pub mod sandbox;
+pub mod system;
+pub mod systemd;
+
+use crate::config::{Config, Settings, ALLOWED_EXTENSION, HOST_NAME};
+use anyhow::{Context, Result};
+use directories::BaseDirs;
+use legacy::PythonBackup;
+use record::InstallRecord;
+use sandbox::Packaging;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use system::SystemLayout;
+
+/// Lists the files an install wrote and the directories it created; kept in the host binary's
+/// directory.
+const INSTALL_RECORD: &str = ".install-record";
+
+#[derive(serde::Serialize)]
+struct Manifest<'a> {
+    name: &'a str,
+    description: &'a str,
+    path: String,
+    r#type: &'a str,
+    allowed_extensions: [&'a str; 1],
+}
+
+/// A manifest as found on disk, possibly written by another host.
+#[derive(Debug, serde::Deserialize)]
+pub struct InstalledManifest {
+    pub name: String,
+    pub path: String,
+    pub r#type: String,
+    #[serde(default)]
+    pub allowed_extensions: Vec<String>,
+}
+
+impl InstalledManifest {
+    pub fn read(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))
+    }
+}
+
+enum Scope {
+    /// User scope; `copy_host` copies the ext binary to `~/.local/libexec/walrusfox` first.
+    User {
+        copy_host: bool,
+    },
+    System(SystemLayout),
+}
+
+pub struct Installer {
+    home: PathBuf,
+    packagings: Vec<Packaging>,
+    scope: Scope,
+}
+
+impl Installer {
+    pub fn new() -> Result<Self> {
+        Self::user(false)
+    }
+
+    pub fn user(copy_host: bool) -> Result<Self> {
+        Self::with_scope(Scope::User { copy_host })
+    }
+
+    /// System-wide install under `prefix` (default `/usr`), staged below `DESTDIR` if set.
+    pub fn system(prefix: Option<PathBuf>) -> Result<Self> {
+        Self::with_scope(Scope::System(SystemLayout::new(prefix)))
+    }
+
+    fn with_scope(scope: Scope) -> Result<Self> {
+        let home = BaseDirs::new()
+            .context("resolving home directory")?
+            .home_dir()
+            .to_path_buf();
+        let packagings = Packaging::detect(&home);
+        Ok(Self {
+            home,
+            packagings,
+            scope,
+        })
+    }
+
+    pub fn install(&self) -> Result<()> {
+        match &self.scope {
+            Scope::User { copy_host } => {
+                if let Some(backup) = PythonBackup::create(&self.manifest_path_user())? {
+                    println!(
+                        "Found a Python pywalfox manifest; backed it up to {}.\n\
+                         Run `walrusfox migrate` to carry over its settings, or `walrusfox uninstall --restore` to switch back.",
+                        backup.manifest.backup.display()
+                    );
+                }
+                self.install_user(*copy_host)
+            }
+            Scope::System(layout) => Self::install_system(&Self::ext_binary_source()?, layout),
+        }
+    }
+
+    pub fn uninstall(&self) -> Result<()> {
+        match &self.scope {
+            Scope::User { .. } => self.uninstall_user(),
+            Scope::System(layout) => Self::uninstall_system(layout),
+        }
+    }
+
+    pub fn home(&self) -> &Path {
+        &self.home
+    }
+
+    /// Every location Firefox may read our manifest from, labelled by packaging/scope.
+    pub fn manifest_locations(&self) -> Vec<(&'static str, PathBuf)> {
+        let mut locations = vec![(Packaging::Native.label(), self.manifest_path_user())];
+        if self.packagings.contains(&Packaging::Flatpak) {
+            locations.push((Packaging::Flatpak.label(), self.manifest_path_flatpak()));
+        }
+        for path in SystemLayout::new(None).manifest_paths() {
+            locations.push(("system", path));
+        }
+        locations
+    }
+
+    fn install_user(&self, copy_host: bool) -> Result<()> {
+        let host = if copy_host {
+            self.copy_host_binary()?
+        } else {
+            let host = Self::ext_binary_source()?;
+            if Self::is_build_output(&host) {
+                println!(
+                    "Warning: {} is a cargo build output and may disappear on `cargo clean`.\n\
+                     Re-run with `walrusfox install --copy` to install a stable copy.",
+                    host.display()
+                );
+            }
+            host
+        };
+        let host = host.display().to_string();
+        for packaging in &self.packagings {
+            match packaging {
+                Packaging::Native => self.install_manifest(&host)?,
+                Packaging::Flatpak => self.install_flatpak(&host)?,
+                Packaging::Snap => Self::check_snap(),
+            }
+        }
+        Ok(())
+    }
+
+    fn uninstall_user(&self) -> Result<()> {
+        for packaging in &self.packagings {
+            match packaging {
+                Packaging::Native => Self::remove_file(&self.manifest_path_user())?,
+                Packaging::Flatpak => {
+                    Self::remove_file(&self.manifest_path_flatpak())?;
+                    Self::remove_file(&sandbox::flatpak_wrapper_path(&self.home))?;
+                }
+                Packaging::Snap => {} // shares the native manifest
+            }
+        }
+        systemd::uninstall(&self.home)?;
+        let copied = self.libexec_host_binary();
+        if copied.exists() {
+            Self::remove_file(&copied)?;
+        }
+        if let Some(dir) = copied.parent() {
+            InstallRecord::load(dir.join(INSTALL_RECORD)).remove();
+        }
+        Ok(())
+    }
+
+    /// Installs `walrusfox.socket`/`walrusfox.service` user units; warns when `socket`, the path
+    /// clients use, is not the one the socket unit listens on.
+    pub fn install_systemd(&self, socket: &Path) -> Result<()> {
+        let exe = std::env::current_exe().context("resolving current executable")?;
+        let cli = exe.canonicalize().unwrap_or(exe);
+        if Self::is_build_output(&cli) {
+            println!(
+                "Warning: the service runs {}, a cargo build output; install walrusfox to a stable path first.",
+                cli.display()
+            );
+        }
+        let unit_socket = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(|dir| Path::new(&dir).join("walrusfox").join("walrusfox.sock"));
+        if unit_socket.as_deref() != Some(socket) {
+            println!(
+                "Warning: the socket unit listens on {}, but walrusfox uses {}; unset WALRUSFOX_SOCKET for the units to be used.",
+                systemd::SOCKET_PATH,
+                socket.display()
+            );
+        }
+        systemd::install(&self.home, &cli)
+    }
+
+    /// Backs up a Python pywalfox install, converts its settings and installs walrusfox over it.
+    pub fn migrate(&self) -> Result<()> {
+        let backup = match PythonBackup::create(&self.manifest_path_user())? {
+            Some(backup) => Some(backup),
+            None => PythonBackup::load()?,
+        };
+        if backup.is_none() && legacy::python_executables().is_empty() {
+            println!("No Python pywalfox installation found; nothing to migrate");
+            return Ok(());
+        }
+        if let Some(backup) = &backup {
+            println!(
+                "Python pywalfox host {} is backed up in {}",
+                backup.host_path.display(),
+                backup.manifest.backup.display()
+            );
+        }
+
+        let mut settings = Settings::load();
+        let colors = legacy::python_colors_file(&self.home);
+        if settings.colors_file.is_none()
+            && !Config::colors_default_path().exists()
+            && colors.exists()
+        {
+            println!("Colors: reading pywal's {}", colors.display());
+            settings.colors_file = Some(colors);
+        }
+        let stylesheets = legacy::custom_css_files(&self.home);
+        if !stylesheets.is_empty() {
+            println!("Custom CSS: walrusfox does not support it, so these pywalfox styles were not migrated:");
+            for css in &stylesheets {
+                println!("  {}", css.display());
+            }
+            println!("  They keep the colors they were last written with; remove the pywalfox rules to drop them.");
+        }
+        let path = settings.save()?;
+        println!("Saved settings to {}", path.display());
+
+        self.install_user(false)
+    }
+
+    /// Puts the manifest saved by `install`/`migrate` back, handing the browser to the Python host.
+    pub fn restore_python(&self) -> Result<()> {
+        let backup =
+            PythonBackup::load()?.context("no Python pywalfox backup found; nothing to restore")?;
+        backup.restore()
+    }
+
+    pub fn print_manifest(&self) -> Result<()> {
+        let host = match &self.scope {
+            Scope::System(layout) => layout.host_binary(),
+            Scope::User { .. } => Self::ext_binary_source()?,
+        };
+        let manifest = Self::build_manifest(host.display().to_string());
+        let data = serde_json::to_string_pretty(&manifest)?;
+        println!("{}", data);
+        Ok(())
+    }
+
+    fn install_manifest(&self, host: &str) -> Result<()> {
+        let manifest = Self::build_manifest(host.to_string());
+        Self::write_manifest(&manifest, &self.manifest_path_user())
+    }
+
+    fn install_flatpak(&self, host: &str) -> Result<()> {
+        let wrapper = sandbox::write_flatpak_wrapper(&self.home, host)?;
+        println!("Installed Flatpak wrapper at {}", wrapper.display());
+
+        let manifest = Self::build_manifest(wrapper.display().to_string());
+        Self::write_manifest(&manifest, &self.manifest_path_flatpak())?;
+
+        if !sandbox::flatpak_can_spawn_host(&self.home) {
+            println!(
+                "Flatpak Firefox is not allowed to start programs outside its sandbox yet.\n\
+                 Grant it once and restart Firefox:\n    {}",
+                sandbox::flatpak_override_hint()
+            );
+        }
+        Ok(())
+    }
+
+    fn check_snap() {
+        // The snap reads host manifests through the WebExtensions portal, so the native
+        // manifest already covers it as long as the portal is present.
+        if sandbox::snap_portal_available() {
+            println!(
+                "Snap Firefox detected; it uses the native manifest via the WebExtensions portal"
+            );
+        } else {
+            println!(
+                "Snap Firefox detected, but the xdg-desktop-portal WebExtensions interface is missing.\n\
+                 Install a recent xdg-desktop-portal and make sure `widget.use-xdg-desktop-portal.native-messaging`\n\
+                 is set to 1 in about:config, otherwise the snap cannot reach walrusfox-ext."
+            );
+        }
+    }
+
+    fn write_manifest(manifest: &Manifest, manifest_path: &Path) -> Result<()> {
+        if let Some(dir) = manifest_path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        let data = serde_json::to_vec_pretty(manifest)?;
+        fs::write(manifest_path, data)
+            .with_context(|| format!("writing manifest {}", manifest_path.display()))?;
+
+        println!("Installed manifest at {}", manifest_path.display());
+
+        Ok(())
+    }
+
+    fn install_system(source: &Path, layout: &SystemLayout) -> Result<()> {
+        let host = layout.host_binary();
+        let host_dir = layout.staged(&layout.host_binary_dir());
+        let mut record = InstallRecord::load(host_dir.join(INSTALL_RECORD));
+        record.create(&host_dir)?;
+        let manifests: Vec<PathBuf> = layout
+            .manifest_paths()
+            .iter()
+            .map(|path| layout.staged(path))
+            .collect();
+        for path in &manifests {
+            if let Some(dir) = path.parent() {
+                record.create(dir)?;
+            }
+            record.add_file(path);
+        }
+        record.add_file(&layout.staged(&host));
+        record.save()?;
+
+        Self::copy_binary(source, &layout.staged(&host))?;
+        let manifest = Self::build_manifest(host.display().to_string());
+        for path in &manifests {
+            Self::write_manifest(&manifest, path)?;
+        }
+
+        if !layout.is_default_prefix() {
+            println!(
+                "Note: Firefox only reads system manifests from /usr/lib/mozilla and /usr/lib64/mozilla;\n\
+                 make sure {} is linked there.",
+                layout.prefix().join("lib").join("mozilla").display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Removes what install recorded, so a `lib64` that appeared or went away since does not
+    /// change which manifests are removed. Without a record (e.g. installed by a package) it
+    /// falls back to the layout's paths.
+    fn uninstall_system(layout: &SystemLayout) -> Result<()> {
+        let host_dir = layout.staged(&layout.host_binary_dir());
+        let record = InstallRecord::load(host_dir.join(INSTALL_RECORD));
+        let files = if record.exists() {
+            record.files().to_vec()
+        } else {
+            let mut files: Vec<PathBuf> = layout
+                .manifest_paths()
+                .iter()
+                .map(|path| layout.staged(path))
+                .collect();
+            files.push(layout.staged(&layout.host_binary()));
+            files
+        };
+        for path in &files {
+            Self::remove_file(path)?;
+        }
+        // Only the directories install created, e.g. not a /usr/lib/mozilla another host uses.
+        record.remove();
+        Ok(())
+    }
+
+    fn copy_host_binary(&self) -> Result<PathBuf> {
+        let source = Self::ext_binary_source()?;
+        let target = self.libexec_host_binary();
+        if let Some(dir) = target.parent() {
+            let mut record = InstallRecord::load(dir.join(INSTALL_RECORD));
+            record.create(dir)?;
+            record.save()?;
+        }
+        Self::copy_binary(&source, &target)?;
+        Ok(target)
+    }
+
+    /// Copies through a temporary file renamed into place: a browser may be running the old
+    /// binary, and writing over it in place fails with ETXTBSY or corrupts it.
+    fn copy_binary(source: &Path, target: &Path) -> Result<()> {
+        if !Self::same_file(source, target) {
+            let name = target.file_name().unwrap_or_default().to_string_lossy();
+            let tmp = target.with_file_name(format!(".{}.tmp", name));
+            fs::copy(source, &tmp)
+                .with_context(|| format!("copying {} to {}", source.display(), tmp.display()))?;
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(0o755))
+                .with_context(|| format!("chmod {}", tmp.display()))?;
+            fs::rename(&tmp, target).with_context(|| {
+                let _ = fs::remove_file(&tmp);
+                format!("moving {} to {}", tmp.display(), target.display())
+            })?;
+        }
+        fs::set_permissions(target, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("chmod {}", target.display()))?;
+        Self::verify_host_binary(target)?;
+        println!("Installed host binary at {}", target.display());
+        Ok(())
+    }
+
+    /// The ext binary shipped next to the running CLI, canonicalized so symlinked CLIs work.
+    fn ext_binary_source() -> Result<PathBuf> {
+        let exe = std::env::current_exe().context("resolving current executable")?;
+        let exe = exe.canonicalize().unwrap_or(exe);
+        let source = exe.with_file_name("walrusfox-ext");
+        Self::verify_host_binary(&source).with_context(|| {
+            format!(
+                "walrusfox-ext must be installed next to {}; build both binaries first",
+                exe.display()
+            )
+        })?;
+        Ok(source)
+    }
+
+    /// Refuses paths Firefox could not launch, so we never write a manifest pointing nowhere.
+    fn verify_host_binary(path: &Path) -> Result<()> {
+        let meta = fs::metadata(path).with_context(|| format!("host binary {}", path.display()))?;
+        if !meta.is_file() {
+            anyhow::bail!("host binary {} is not a regular file", path.display());
+        }
+        if meta.permissions().mode() & 0o111 == 0 {
+            anyhow::bail!("host binary {} is not executable", path.display());
+        }
+        Ok(())
+    }
+
+    /// Whether `path` lies in a cargo target directory (any profile, target triple or
+    /// `CARGO_TARGET_DIR`), which cargo marks with `CACHEDIR.TAG` and `.rustc_info.json`.
+    fn is_build_output(path: &Path) -> bool {
+        path.ancestors()
+            .skip(1)
+            .any(|dir| dir.join(".rustc_info.json").is_file() || dir.join("CACHEDIR.TAG").is_file())
+    }
+
+    fn same_file(a: &Path, b: &Path) -> bool {
+        match (a.canonicalize(), b.canonicalize()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    fn build_manifest(path: String) -> Manifest<'static> {
+        Manifest {
+            name: HOST_NAME,
+            description: "Automatically theme your browser using external colors",
+            path,
+            r#type: "stdio",
+            allowed_extensions: [ALLOWED_EXTENSION],
+        }
+    }
+
+    fn remove_file(path: &Path) -> Result<()> {
+        if path.exists() {
+            fs::remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+            println!("Removed {}", path.display());
+        } else {
+            println!("Not found at {}", path.display());
+        }
+
+        Ok(())
+    }
+
+    fn manifest_path_user(&self) -> PathBuf {
+        self.home
+            .join(".mozilla")
+            .join("native-messaging-hosts")
+            .join(format!("{}.json", HOST_NAME))
+    }
+
+    fn libexec_host_binary(&self) -> PathBuf {
+        self.home
+            .join(".local")
+            .join("libexec")
+            .join("walrusfox")
+            .join("walrusfox-ext")
+    }
+
+    fn manifest_path_flatpak(&self) -> PathBuf {
+        sandbox::flatpak_manifest_dir(&self.home).join(format!("{}.json", HOST_NAME))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("walrusfox-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn recognizes_cargo_target_directories() {
+        let root = scratch("build-output");
+        let nested = root.join("target/x86_64-unknown-linux-gnu/release");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.join("target/CACHEDIR.TAG"), "").unwrap();
+        let custom = root.join("out/debug");
+        fs::create_dir_all(&custom).unwrap();
+        fs::write(root.join("out/.rustc_info.json"), "{}").unwrap();
+        let libexec = root.join("libexec/walrusfox");
+        fs::create_dir_all(&libexec).unwrap();
+
+        assert!(Installer::is_build_output(&nested.join("walrusfox-ext")));
+        assert!(Installer::is_build_output(&custom.join("walrusfox-ext")));
+        assert!(!Installer::is_build_output(&libexec.join("walrusfox-ext")));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn host_binary_must_be_an_executable_file() {
+        let root = scratch("verify-host");
+        let host = root.join("walrusfox-ext");
+        assert!(Installer::verify_host_binary(&host).is_err());
+        fs::write(&host, "").unwrap();
+        fs::set_permissions(&host, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(Installer::verify_host_binary(&host).is_err());
+        fs::set_permissions(&host, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(Installer::verify_host_binary(&host).is_ok());
+        assert!(Installer::verify_host_binary(&root).is_err());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn system_uninstall_removes_what_install_recorded() {
+        let root = scratch("system-record");
+        let source = root.join("walrusfox-ext");
+        fs::write(&source, "").unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o755)).unwrap();
+        let prefix = root.join("usr");
+        let layout = SystemLayout::new(Some(prefix.clone()));
+        Installer::install_system(&source, &layout).unwrap();
+        let manifest = layout.staged(&layout.manifest_paths()[0]);
+        assert!(manifest.exists());
+
+        // A lib64 that shows up later, with a manifest install never wrote, is left alone.
+        let lib64 = prefix.join("lib64/mozilla/native-messaging-hosts");
+        fs::create_dir_all(&lib64).unwrap();
+        fs::write(lib64.join(manifest.file_name().unwrap()), "{}").unwrap();
+        Installer::uninstall_system(&layout).unwrap();
+        assert!(!manifest.exists());
+        assert!(!prefix.join("lib").exists());
+        assert!(!prefix.join("libexec").exists());
+        assert!(lib64.join(manifest.file_name().unwrap()).exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What an install wrote: the files, and the directories it had to create. Kept in a small file
+/// next to what was installed so that uninstall removes exactly those, never a directory that
+/// existed before, and never decides again where the files would go.
+#[derive(Debug)]
+pub struct InstallRecord {
+    path: PathBuf,
+    dirs: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+}
+
+impl InstallRecord {
+    /// The record at `path`; empty when there is none (e.g. nothing was installed).
+    pub fn load(path: PathBuf) -> Self {
+        let mut record = Self {
+            path,
+            dirs: Vec::new(),
+            files: Vec::new(),
+        };
+        let data = fs::read_to_string(&record.path).unwrap_or_default();
+        for line in data.lines() {
+            match line.split_once(' ') {
+                Some(("dir", dir)) => record.dirs.push(PathBuf::from(dir)),
+                Some(("file", file)) => record.files.push(PathBuf::from(file)),
+                _ => {}
+            }
+        }
+        record
+    }
+
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Creates `dir` and its missing parents, remembering each one that did not exist yet.
+    pub fn create(&mut self, dir: &Path) -> Result<()> {
+        let missing: Vec<PathBuf> = dir
+            .ancestors()
+            .take_while(|d| !d.as_os_str().is_empty() && !d.exists())
+            .map(Path::to_path_buf)
+            .collect();
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        for dir in missing {
+            if !self.dirs.contains(&dir) {
+                self.dirs.push(dir);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remembers a file the install writes.
+    pub fn add_file(&mut self, file: &Path) {
+        if !self.files.iter().any(|f| f == file) {
+            self.files.push(file.to_path_buf());
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let dirs = self.dirs.iter().map(|d| format!("dir {}\n", d.display()));
+        let files = self.files.iter().map(|f| format!("file {}\n", f.display()));
+        let data: String = dirs.chain(files).collect();
+        fs::write(&self.path, data).with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    /// Deletes the record, then the recorded directories deepest first. Directories that are
+    /// not empty (the user put something there since) are kept. Recorded files are the caller's
+    /// to remove first.
+    pub fn remove(mut self) {
+        let _ = fs::remove_file(&self.path);
+        self.dirs
+            .sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+        for dir in &self.dirs {
+            if fs::remove_dir(dir).is_ok() {
+                println!("Removed {}", dir.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_only_directories_it_created() {
+        let root = std::env::temp_dir().join(format!("walrusfox-record-{}", std::process::id()));
+        let existing = root.join("lib");
+        fs::create_dir_all(&existing).unwrap();
+        let record = root.join("record");
+        let manifest = existing.join("mozilla/native-messaging-hosts/pywalfox.json");
+
+        let mut created = InstallRecord::load(record.clone());
+        created
+            .create(&existing.join("mozilla/native-messaging-hosts"))
+            .unwrap();
+        created.add_file(&manifest);
+        created.save().unwrap();
+        // A second install finds everything in place but must not forget the first one's dirs.
+        let mut again = InstallRecord::load(record.clone());
+        again
+            .create(&existing.join("mozilla/native-messaging-hosts"))
+            .unwrap();
+        again.add_file(&manifest);
+        again.save().unwrap();
+
+        let loaded = InstallRecord::load(record.clone());
+        assert_eq!(loaded.files(), [manifest]);
+        loaded.remove();
+        assert!(!existing.join("mozilla").exists());
+        assert!(existing.exists());
+        assert!(!record.exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
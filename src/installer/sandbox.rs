@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+pub const FLATPAK_APP_ID: &str = "org.mozilla.firefox";
+const FLATPAK_TALK_NAME: &str = "org.freedesktop.Flatpak";
+const FLATPAK_SYSTEM_OVERRIDES: &str = "/var/lib/flatpak/overrides";
+const SNAP_BIN: &str = "/snap/bin/firefox";
+const WEBEXTENSIONS_PORTAL: &str =
+    "/usr/share/dbus-1/interfaces/org.freedesktop.portal.WebExtensions.xml";
+
+/// How Firefox is packaged; decides where the manifest must live and how the host is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packaging {
+    Native,
+    Flatpak,
+    Snap,
+}
+
+impl Packaging {
+    /// Native is always included; sandboxed packagings are added when their data dirs exist.
+    pub fn detect(home: &Path) -> Vec<Packaging> {
+        let mut found = vec![Packaging::Native];
+        if flatpak_app_dir(home).is_dir() {
+            found.push(Packaging::Flatpak);
+        }
+        if home.join("snap").join("firefox").is_dir() || Path::new(SNAP_BIN).exists() {
+            found.push(Packaging::Snap);
+        }
+        found
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Packaging::Native => "native",
+            Packaging::Flatpak => "flatpak",
+            Packaging::Snap => "snap",
+        }
+    }
+}
+
+fn flatpak_app_dir(home: &Path) -> PathBuf {
+    home.join(".var").join("app").join(FLATPAK_APP_ID)
+}
+
+/// Manifest directory as seen from inside the Flatpak sandbox (the app dir keeps its host path).
+pub fn flatpak_manifest_dir(home: &Path) -> PathBuf {
+    flatpak_app_dir(home)
+        .join(".mozilla")
+        .join("native-messaging-hosts")
+}
+
+pub fn flatpak_wrapper_path(home: &Path) -> PathBuf {
+    flatpak_app_dir(home)
+        .join("data")
+        .join("walrusfox")
+        .join("walrusfox-ext.sh")
+}
+
+/// Writes a wrapper inside the sandbox that launches the real host outside of it.
+pub fn write_flatpak_wrapper(home: &Path, host_bin: &str) -> Result<PathBuf> {
+    let wrapper = flatpak_wrapper_path(home);
+    if let Some(dir) = wrapper.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    let script = format!(
+        "#!/bin/sh\n\
+         # Generated by walrusfox: Flatpak Firefox cannot execute binaries outside its sandbox,\n\
+         # so the native host is started on the host system through flatpak-spawn.\n\
+         exec flatpak-spawn --host {} \"$@\"\n",
+        shell_quote(host_bin)
+    );
+    fs::write(&wrapper, script).with_context(|| format!("writing {}", wrapper.display()))?;
+    fs::set_permissions(&wrapper, fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("chmod {}", wrapper.display()))?;
+    Ok(wrapper)
+}
+
+/// `flatpak-spawn --host` only works when the app may talk to `org.freedesktop.Flatpak`.
+pub fn flatpak_can_spawn_host(home: &Path) -> bool {
+    let user = home
+        .join(".local")
+        .join("share")
+        .join("flatpak")
+        .join("overrides")
+        .join(FLATPAK_APP_ID);
+    let system = Path::new(FLATPAK_SYSTEM_OVERRIDES).join(FLATPAK_APP_ID);
+    [user, system].iter().any(|path| {
+        fs::read_to_string(path)
+            .map(|data| overrides_allow_host_spawn(&data))
+            .unwrap_or(false)
+    })
+}
+
+fn overrides_allow_host_spawn(data: &str) -> bool {
+    let wanted = format!("{}=talk", FLATPAK_TALK_NAME);
+    data.lines().any(|line| line.trim() == wanted)
+}
+
+pub fn flatpak_override_hint() -> String {
+    format!(
+        "flatpak override --user --talk-name={} {}",
+        FLATPAK_TALK_NAME, FLATPAK_APP_ID
+    )
+}
+
+/// Snap Firefox reaches native hosts through the WebExtensions portal of xdg-desktop-portal.
+pub fn snap_portal_available() -> bool {
+    Path::new(WEBEXTENSIONS_PORTAL).exists()
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_detect_talk_permission() {
+        let data = "[Session Bus Policy]\norg.freedesktop.Flatpak=talk\n";
+        assert!(overrides_allow_host_spawn(data));
+        assert!(!overrides_allow_host_spawn(
+            "[Context]\nfilesystems=home;\n"
+        ));
+    }
+
+    #[test]
+    fn shell_quote_escapes_single_quotes() {
+        assert_eq!(shell_quote("/opt/it's/bin"), r"'/opt/it'\''s/bin'");
+    }
+}
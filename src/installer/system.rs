@@ -0,0 +1,99 @@
+use crate::config::HOST_NAME;
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+const DEFAULT_PREFIX: &str = "/usr";
+
+/// File layout of a system-wide install.
+///
+/// Paths returned here are the runtime paths recorded in the manifest; use [`SystemLayout::staged`]
+/// to get the location actually written to, which honors `DESTDIR` for packaging.
+#[derive(Debug, Clone)]
+pub struct SystemLayout {
+    prefix: PathBuf,
+    destdir: Option<PathBuf>,
+}
+
+impl SystemLayout {
+    pub fn new(prefix: Option<PathBuf>) -> Self {
+        let destdir = env::var_os("DESTDIR")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from);
+        Self::with_destdir(prefix, destdir)
+    }
+
+    fn with_destdir(prefix: Option<PathBuf>, destdir: Option<PathBuf>) -> Self {
+        Self {
+            prefix: prefix.unwrap_or_else(|| PathBuf::from(DEFAULT_PREFIX)),
+            destdir,
+        }
+    }
+
+    pub fn prefix(&self) -> &Path {
+        &self.prefix
+    }
+
+    /// Firefox only searches `/usr/lib{,64}/mozilla` for system manifests.
+    pub fn is_default_prefix(&self) -> bool {
+        self.prefix == Path::new(DEFAULT_PREFIX)
+    }
+
+    pub fn host_binary_dir(&self) -> PathBuf {
+        self.prefix.join("libexec").join("walrusfox")
+    }
+
+    pub fn host_binary(&self) -> PathBuf {
+        self.host_binary_dir().join("walrusfox-ext")
+    }
+
+    /// `lib` always; `lib64` too on distributions where it is a real directory (Fedora, openSUSE).
+    pub fn manifest_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = vec![self.prefix.join("lib")];
+        let lib64 = self.prefix.join("lib64");
+        let is_real_dir = |p: &Path| p.symlink_metadata().map(|m| m.is_dir()).unwrap_or(false);
+        if is_real_dir(&lib64) || is_real_dir(&self.staged(&lib64)) {
+            dirs.push(lib64);
+        }
+        dirs.into_iter()
+            .map(|lib| lib.join("mozilla").join("native-messaging-hosts"))
+            .collect()
+    }
+
+    pub fn manifest_paths(&self) -> Vec<PathBuf> {
+        self.manifest_dirs()
+            .into_iter()
+            .map(|dir| dir.join(format!("{}.json", HOST_NAME)))
+            .collect()
+    }
+
+    pub fn staged(&self, path: &Path) -> PathBuf {
+        match &self.destdir {
+            Some(destdir) => {
+                let relative: PathBuf = path
+                    .components()
+                    .filter(|c| !matches!(c, Component::RootDir))
+                    .collect();
+                destdir.join(relative)
+            }
+            None => path.to_path_buf(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn staged_paths_honor_destdir() {
+        let layout = SystemLayout::with_destdir(None, Some(PathBuf::from("/tmp/pkg")));
+        assert_eq!(
+            layout.staged(&layout.host_binary()),
+            PathBuf::from("/tmp/pkg/usr/libexec/walrusfox/walrusfox-ext")
+        );
+        assert_eq!(
+            layout.manifest_paths()[0],
+            PathBuf::from("/usr/lib/mozilla/native-messaging-hosts/pywalfox.json")
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub const SOCKET_UNIT: &str = "walrusfox.socket";
+pub const SERVICE_UNIT: &str = "walrusfox.service";
+
+pub fn unit_dir(home: &Path) -> PathBuf {
+    home.join(".config").join("systemd").join("user")
+}
+
+/// Where the socket unit listens: the server's default path, with `%t` being `$XDG_RUNTIME_DIR`.
+pub const SOCKET_PATH: &str = "%t/walrusfox/walrusfox.sock";
+
+pub fn socket_unit() -> String {
+    format!(
+        "[Unit]\n\
+         Description=walrusfox control socket\n\
+         \n\
+         [Socket]\n\
+         ListenStream={SOCKET_PATH}\n\
+         SocketMode=0600\n\
+         DirectoryMode=0700\n\
+         RemoveOnStop=yes\n\
+         \n\
+         [Install]\n\
+         WantedBy=sockets.target\n"
+    )
+}
+
+pub fn service_unit(cli: &Path) -> String {
+    format!(
+        "[Unit]\n\
+         Description=walrusfox socket server for the Pywalfox extension\n\
+         Requires={SOCKET_UNIT}\n\
+         After={SOCKET_UNIT}\n\
+         \n\
+         [Service]\n\
+         Type=notify\n\
+         ExecStart=\"{}\" start\n\
+         WatchdogSec=30\n\
+         Restart=on-failure\n\
+         \n\
+         [Install]\n\
+         Also={SOCKET_UNIT}\n",
+        cli.display()
+    )
+}
+
+/// Writes both units and enables the socket, so the server starts on the first connection.
+pub fn install(home: &Path, cli: &Path) -> Result<()> {
+    let dir = unit_dir(home);
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    for (name, contents) in [
+        (SOCKET_UNIT, socket_unit()),
+        (SERVICE_UNIT, service_unit(cli)),
+    ] {
+        let path = dir.join(name);
+        fs::write(&path, contents).with_context(|| format!("writing {}", path.display()))?;
+        println!("Installed unit {}", path.display());
+    }
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", "--now", SOCKET_UNIT])?;
+    println!("Enabled {SOCKET_UNIT}; the server now starts on demand");
+    Ok(())
+}
+
+/// Stops and removes the units again; a no-op when they were never installed.
+pub fn uninstall(home: &Path) -> Result<()> {
+    let dir = unit_dir(home);
+    let units: Vec<PathBuf> = [SOCKET_UNIT, SERVICE_UNIT]
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.exists())
+        .collect();
+    if units.is_empty() {
+        return Ok(());
+    }
+    if let Err(e) = systemctl(&["disable", "--now", SOCKET_UNIT, SERVICE_UNIT]) {
+        println!("Warning: {e:#}");
+    }
+    for path in units {
+        fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+        println!("Removed unit {}", path.display());
+    }
+    systemctl(&["daemon-reload"])
+}
+
+fn systemctl(args: &[&str]) -> Result<()> {
+    let status = Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .status()
+        .context("running systemctl --user (is systemd available?)")?;
+    if !status.success() {
+        anyhow::bail!("systemctl --user {} failed ({})", args.join(" "), status);
+    }
+    Ok(())
+}
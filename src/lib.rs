@@ -2,7 +2,9 @@
 pub mod bridge;
 pub mod client;
 pub mod config;
+pub mod doctor;
 pub mod installer;
+pub mod profiles;
 pub mod protocol;
 pub mod server;
 pub mod utils;
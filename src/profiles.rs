@@ -0,0 +1,253 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Profile roots (relative to `$HOME`) of Firefox, its packagings and forks.
+const BROWSER_ROOTS: &[(&str, &str)] = &[
+    ("firefox", ".mozilla/firefox"),
+    (
+        "firefox-flatpak",
+        ".var/app/org.mozilla.firefox/.mozilla/firefox",
+    ),
+    ("firefox-snap", "snap/firefox/common/.mozilla/firefox"),
+    ("librewolf", ".librewolf"),
+    (
+        "librewolf-flatpak",
+        ".var/app/io.gitlab.librewolf-community/.librewolf",
+    ),
+    ("waterfox", ".waterfox"),
+    ("floorp", ".floorp"),
+    ("zen", ".zen"),
+    ("thunderbird", ".thunderbird"),
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Profile {
+    pub name: String,
+    pub path: PathBuf,
+    /// Legacy `Default=1` marker from `profiles.ini`.
+    pub is_default: bool,
+    /// Install hashes (from `installs.ini`) that use this profile by default.
+    pub default_for: Vec<String>,
+}
+
+/// One browser installation; Firefox keys these by a hash of the install directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct Install {
+    pub id: String,
+    pub default_profile: PathBuf,
+    pub locked: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BrowserProfiles {
+    pub browser: &'static str,
+    pub root: PathBuf,
+    pub profiles: Vec<Profile>,
+    pub installs: Vec<Install>,
+}
+
+impl BrowserProfiles {
+    /// The profile an install opens by default, falling back to `Default=1` or a lone profile.
+    pub fn default_profile(&self) -> Option<&Profile> {
+        self.profiles
+            .iter()
+            .find(|p| !p.default_for.is_empty())
+            .or_else(|| self.profiles.iter().find(|p| p.is_default))
+            .or(match self.profiles.as_slice() {
+                [only] => Some(only),
+                _ => None,
+            })
+    }
+
+    fn load(browser: &'static str, root: PathBuf) -> Result<Self> {
+        let ini = root.join("profiles.ini");
+        let data =
+            fs::read_to_string(&ini).with_context(|| format!("reading {}", ini.display()))?;
+        let sections = parse_ini(&data);
+
+        let mut profiles = Vec::new();
+        let mut installs = Vec::new();
+        for (section, entries) in &sections {
+            let get = |key: &str| {
+                entries
+                    .iter()
+                    .find(|(k, _)| k == key)
+                    .map(|(_, v)| v.as_str())
+            };
+            if section.starts_with("Profile") {
+                let Some(path) = get("Path") else { continue };
+                let relative = get("IsRelative") != Some("0");
+                profiles.push(Profile {
+                    name: get("Name").unwrap_or(path).to_string(),
+                    path: resolve(&root, path, relative),
+                    is_default: get("Default") == Some("1"),
+                    default_for: Vec::new(),
+                });
+            } else if let Some(id) = section.strip_prefix("Install") {
+                // Firefox mirrors installs.ini into profiles.ini as [Install<hash>] sections.
+                if let Some(install) = Self::install(&root, id, entries) {
+                    installs.push(install);
+                }
+            }
+        }
+
+        if let Ok(data) = fs::read_to_string(root.join("installs.ini")) {
+            for (id, entries) in &parse_ini(&data) {
+                if installs.iter().any(|i| &i.id == id) {
+                    continue;
+                }
+                if let Some(install) = Self::install(&root, id, entries) {
+                    installs.push(install);
+                }
+            }
+        }
+
+        for install in &installs {
+            if let Some(profile) = profiles
+                .iter_mut()
+                .find(|p| p.path == install.default_profile)
+            {
+                profile.default_for.push(install.id.clone());
+            }
+        }
+
+        Ok(Self {
+            browser,
+            root,
+            profiles,
+            installs,
+        })
+    }
+
+    fn install(root: &Path, id: &str, entries: &[(String, String)]) -> Option<Install> {
+        let get = |key: &str| entries.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+        let default = get("Default")?;
+        Some(Install {
+            id: id.to_string(),
+            default_profile: resolve(root, default, !Path::new(default).is_absolute()),
+            locked: get("Locked").map(String::as_str) == Some("1"),
+        })
+    }
+}
+
+/// All browsers under `home` that have a `profiles.ini`.
+pub fn discover(home: &Path) -> Vec<BrowserProfiles> {
+    BROWSER_ROOTS
+        .iter()
+        .map(|(browser, root)| (*browser, home.join(root)))
+        .filter(|(_, root)| root.join("profiles.ini").is_file())
+        .filter_map(|(browser, root)| BrowserProfiles::load(browser, root).ok())
+        .collect()
+}
+
+/// The profile a running browser holds, found through the `lock` symlink (`<ip>:+<pid>`).
+pub fn running_profile(home: &Path, pid: u32) -> Option<(&'static str, Profile)> {
+    let suffix = format!("+{pid}");
+    discover(home).into_iter().find_map(|browser| {
+        let name = browser.browser;
+        browser
+            .profiles
+            .into_iter()
+            .find(|p| {
+                fs::read_link(p.path.join("lock"))
+                    .map(|target| target.to_string_lossy().ends_with(&suffix))
+                    .unwrap_or(false)
+            })
+            .map(|p| (name, p))
+    })
+}
+
+pub fn print_list(home: &Path, json: bool) -> Result<()> {
+    let browsers = discover(home);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&browsers)?);
+        return Ok(());
+    }
+    if browsers.is_empty() {
+        println!("No browser profiles found under {}", home.display());
+        return Ok(());
+    }
+    for browser in &browsers {
+        println!("{} ({})", browser.browser, browser.root.display());
+        let default = browser.default_profile().map(|p| p.path.clone());
+        for profile in &browser.profiles {
+            let marker = if Some(&profile.path) == default.as_ref() {
+                "*"
+            } else {
+                " "
+            };
+            print!(
+                "  {} {:<20} {}",
+                marker,
+                profile.name,
+                profile.path.display()
+            );
+            if !profile.default_for.is_empty() {
+                print!("  (default for install {})", profile.default_for.join(", "));
+            }
+            println!();
+        }
+    }
+    Ok(())
+}
+
+fn resolve(root: &Path, path: &str, relative: bool) -> PathBuf {
+    if relative {
+        root.join(path)
+    } else {
+        PathBuf::from(path)
+    }
+}
+
+/// Minimal INI reader for Mozilla's files: `[Section]` headers and `key=value` lines.
+fn parse_ini(data: &str) -> Vec<(String, Vec<(String, String)>)> {
+    let mut sections: Vec<(String, Vec<(String, String)>)> = Vec::new();
+    for line in data.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((name.to_string(), Vec::new()));
+        } else if let (Some((key, value)), Some((_, entries))) =
+            (line.split_once('='), sections.last_mut())
+        {
+            entries.push((key.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn install_default_wins_over_legacy_default() {
+        let dir = std::env::temp_dir().join(format!("walrusfox-profiles-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("profiles.ini"),
+            "[Profile1]\nName=default\nIsRelative=1\nPath=a.default\nDefault=1\n\n\
+             [Profile0]\nName=default-release\nIsRelative=1\nPath=b.default-release\n\n\
+             [General]\nStartWithLastProfile=1\nVersion=2\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("installs.ini"),
+            "[4F96D1932A9F858E]\nDefault=b.default-release\nLocked=1\n",
+        )
+        .unwrap();
+
+        let browser = BrowserProfiles::load("firefox", dir.clone()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(browser.profiles.len(), 2);
+        assert_eq!(browser.installs.len(), 1);
+        assert!(browser.installs[0].locked);
+        let default = browser.default_profile().unwrap();
+        assert_eq!(default.name, "default-release");
+        assert_eq!(default.default_for, vec!["4F96D1932A9F858E".to_string()]);
+    }
+}
@@ -1,2 +1,3 @@
 pub mod events;
 pub mod native_messaging;
+pub mod socket;
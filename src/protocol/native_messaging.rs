@@ -5,6 +5,7 @@ use anyhow::{bail, Context, Result};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::io::{self, stdin, Read, Write};
+use std::path::Path;
 use tracing::{error, info, warn};
 
 #[derive(Debug, Deserialize)]
@@ -27,8 +28,13 @@ pub struct ColorData {
 }
 
 pub fn read_message<T: DeserializeOwned + std::fmt::Debug>() -> Result<Option<T>> {
+    read_message_from(&mut stdin())
+}
+
+/// Reads one length-prefixed frame from any reader; `None` on EOF.
+pub fn read_message_from<T: DeserializeOwned, R: Read>(reader: &mut R) -> Result<Option<T>> {
     let mut len_buf = [0u8; 4];
-    if stdin().read_exact(&mut len_buf).is_err() {
+    if reader.read_exact(&mut len_buf).is_err() {
         // EOF or no more input from browser; treat as graceful shutdown
         warn!("Native messaging: EOF while reading message length");
         return Ok(None);
@@ -42,7 +48,7 @@ pub fn read_message<T: DeserializeOwned + std::fmt::Debug>() -> Result<Option<T>
         );
     }
     let mut data = vec![0u8; len];
-    stdin()
+    reader
         .read_exact(&mut data)
         .context("Reading native message body")?;
     let value = decode_message::<T>(&data)?;
@@ -70,29 +76,33 @@ pub fn send_version() -> Result<()> {
     write_message(&response)
 }
 
-pub fn send_colors() -> Result<()> {
-    match themes::read_colors() {
+/// Sends the colors from `colors_file`; returns false (after telling the browser) if loading failed.
+pub fn send_colors(colors_file: &Path) -> Result<bool> {
+    match themes::read_colors(colors_file) {
         Ok(colors) => {
-            let response = Response {
-                action: BrowserAction::Colors.value().to_string(),
-                success: true,
-                error: None,
-                data: Some(ColorData {
-                    colors: colors.0,
-                    wallpaper: colors.1,
-                }),
-            };
-            info!("Sending =>  {:?}", response);
-            write_message(&response)
+            send_palette(colors.0, colors.1)?;
+            Ok(true)
         }
         Err(e) => {
             error!("Failed to load colors: {}", e);
             let err_msg = "Failed to load colors";
-            send_error_response(BrowserAction::Colors, err_msg)
+            send_error_response(BrowserAction::Colors, err_msg)?;
+            Ok(false)
         }
     }
 }
 
+pub fn send_palette(colors: Vec<String>, wallpaper: Option<String>) -> Result<()> {
+    let response = Response {
+        action: BrowserAction::Colors.value().to_string(),
+        success: true,
+        error: None,
+        data: Some(ColorData { colors, wallpaper }),
+    };
+    info!("Sending =>  {:?}", response);
+    write_message(&response)
+}
+
 pub fn send_theme_mode(mode: &str) -> Result<()> {
     let response = Response {
         action: BrowserAction::ThemeMode.value().to_string(),
@@ -111,9 +121,13 @@ pub fn send_invalid_response() -> Result<()> {
 }
 
 fn write_message<T: Serialize>(value: &T) -> Result<()> {
+    write_message_to(&mut io::stdout(), value)
+}
+
+/// Writes one length-prefixed frame to any writer.
+pub fn write_message_to<T: Serialize, W: Write>(out: &mut W, value: &T) -> Result<()> {
     let data = encode_message(value)?;
     let len = data.len() as u32;
-    let mut out = io::stdout();
     out.write_all(&len.to_le_bytes()).context("Write len")?;
     out.write_all(&data).context("Write body")?;
     out.flush().context("Flush stdout")?;
@@ -161,6 +175,14 @@ mod tests {
         assert_eq!(value, out);
     }
 
+    #[test]
+    fn frame_roundtrip() {
+        let mut buf = Vec::new();
+        write_message_to(&mut buf, &T { a: 7 }).expect("write");
+        let out: Option<T> = read_message_from(&mut buf.as_slice()).expect("read");
+        assert_eq!(out, Some(T { a: 7 }));
+    }
+
     #[test]
     fn decode_rejects_invalid_json() {
         let bad = b"not json";
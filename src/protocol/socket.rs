@@ -0,0 +1,475 @@
+use crate::protocol::events::SocketCommand;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// One line of the JSON protocol on the Unix socket.
+///
+/// Legacy bare words (`update`, `dark`, ...) are still accepted and read as id-less commands.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(default = "default_version")]
+    pub v: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: MessageType,
+    /// Browser the message is meant for (e.g. `firefox` or a profile name); `None` means all.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub payload: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageType {
+    /// First message of a connection, declaring its role (see [`Hello`]).
+    Hello,
+    Command,
+    Result,
+    /// Server notifications for controllers that subscribed in their `hello`.
+    Event,
+    /// A question for the server itself (see [`Query`]), answered with a `result`.
+    Query,
+    /// Sent to every connection right before the server shuts down; `payload.reason` says why.
+    Goodbye,
+    /// Heartbeat; the receiver answers with a `pong` carrying the same id.
+    Ping,
+    Pong,
+}
+
+/// What a connection is: a browser host (bridge) that executes commands, or a controller (CLI,
+/// scripts) that sends them. Connections that never say `hello` are controllers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Host,
+    #[default]
+    Controller,
+}
+
+/// Payload of a `hello` envelope.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub browser: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Controllers only: receive `event` envelopes.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub subscribe: bool,
+}
+
+/// Payload of a `query` envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "query", rename_all = "snake_case")]
+pub enum Query {
+    /// Every other connection as a list of [`ClientInfo`].
+    Clients,
+    /// The theme in effect and server counters as a [`ServerStatus`].
+    Status,
+    /// The colors hosts were last told to apply as a [`Palette`].
+    Palette,
+}
+
+/// What the server last applied; it is the source of truth for the browsers' mode.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ThemeStatus {
+    pub mode: ThemeMode,
+    pub palette: Option<PaletteStatus>,
+    /// Unix timestamp of the last `update` or `mode` command.
+    pub updated_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaletteStatus {
+    /// Short fingerprint of the colors and wallpaper; unreadable colors files have none.
+    pub hash: Option<String>,
+    /// Path of the colors file, or `inline` for palettes sent with the command.
+    pub source: String,
+    pub colors: usize,
+    pub wallpaper: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub version: String,
+    pub pid: u32,
+    pub started_at: u64,
+    pub hosts: usize,
+    pub controllers: usize,
+    /// Clients disconnected because they stopped reading what the server sent them.
+    #[serde(default)]
+    pub evicted: u64,
+    pub theme: ThemeStatus,
+}
+
+/// One connection as the server sees it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub role: Role,
+    /// Peer process and user, from `SO_PEERCRED`.
+    pub pid: Option<u32>,
+    pub uid: Option<u32>,
+    pub browser: Option<String>,
+    pub profile: Option<String>,
+    pub subscribed: bool,
+    /// Unix timestamps in seconds.
+    pub connected_at: u64,
+    pub last_command: Option<String>,
+    pub last_command_at: Option<u64>,
+    /// Rejected messages, failed results and unanswered commands.
+    pub errors: u64,
+    /// Bytes waiting to be written to the connection.
+    #[serde(default)]
+    pub queued: usize,
+    /// Round-trip time of the last answered heartbeat, in milliseconds.
+    #[serde(default)]
+    pub rtt_ms: Option<f64>,
+}
+
+impl ClientInfo {
+    /// `firefox (default-release)`, or `client <id>` before a host said `hello`.
+    pub fn label(&self) -> String {
+        match (&self.browser, &self.profile) {
+            (Some(browser), Some(profile)) => format!("{} ({})", browser, profile),
+            (Some(browser), None) => browser.clone(),
+            _ => format!("client {}", self.id),
+        }
+    }
+}
+
+/// Payload of an `event` envelope.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    HostConnected {
+        host: String,
+    },
+    HostDisconnected {
+        host: String,
+    },
+    /// A command was relayed to the hosts.
+    Command {
+        command: Command,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThemeMode {
+    Dark,
+    Light,
+    #[default]
+    Auto,
+}
+
+impl ThemeMode {
+    pub fn value(&self) -> &'static str {
+        match self {
+            ThemeMode::Dark => "dark",
+            ThemeMode::Light => "light",
+            ThemeMode::Auto => "auto",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CssTarget {
+    #[serde(rename = "userChrome")]
+    UserChrome,
+    #[serde(rename = "userContent")]
+    UserContent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Palette {
+    pub colors: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wallpaper: Option<String>,
+}
+
+/// Payload of a `command` envelope.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    /// Re-theme from the colors file, or from `palette` when given inline.
+    Update {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        palette: Option<Palette>,
+    },
+    Mode {
+        mode: ThemeMode,
+    },
+    Css {
+        target: CssTarget,
+        enabled: bool,
+    },
+}
+
+impl Command {
+    /// Whether repeating the command has no further effect, so bursts of it may be coalesced.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Command::Update { .. } | Command::Mode { .. } => true,
+            // CSS toggles rewrite profile files; deliver every one of them.
+            Command::Css { .. } => false,
+        }
+    }
+
+    /// Short human-readable form, e.g. `mode dark`.
+    pub fn summary(&self) -> String {
+        match self {
+            Command::Update { palette: None } => "update".into(),
+            Command::Update { palette: Some(_) } => "update (inline palette)".into(),
+            Command::Mode { mode } => format!("mode {}", mode.value()),
+            Command::Css { target, enabled } => {
+                let state = if *enabled { "on" } else { "off" };
+                format!("css {:?} {}", target, state)
+            }
+        }
+    }
+
+    pub fn from_legacy(cmd: &SocketCommand) -> Option<Self> {
+        Some(match cmd {
+            SocketCommand::Update => Command::Update { palette: None },
+            SocketCommand::Dark => Command::Mode {
+                mode: ThemeMode::Dark,
+            },
+            SocketCommand::Light => Command::Mode {
+                mode: ThemeMode::Light,
+            },
+            SocketCommand::Auto => Command::Mode {
+                mode: ThemeMode::Auto,
+            },
+            SocketCommand::Unknown(_) => return None,
+        })
+    }
+}
+
+/// Payload of a `result` envelope.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The browser (and profile) that answered, e.g. `firefox (default-release)`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Set when the command's `target` did not match this host.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub data: Value,
+}
+
+impl Reply {
+    pub fn ok(data: Value) -> Self {
+        Self {
+            ok: true,
+            error: None,
+            host: None,
+            skipped: false,
+            data,
+        }
+    }
+
+    pub fn error(error: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.into()),
+            host: None,
+            skipped: false,
+            data: Value::Null,
+        }
+    }
+
+    pub fn skipped() -> Self {
+        Self {
+            skipped: true,
+            ..Self::ok(Value::Null)
+        }
+    }
+
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Per-host replies collected by the server for one command (`data.results`).
+    pub fn results(&self) -> Vec<Reply> {
+        self.data
+            .get("results")
+            .and_then(|r| serde_json::from_value(r.clone()).ok())
+            .unwrap_or_default()
+    }
+}
+
+fn default_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+impl Envelope {
+    pub fn command(command: &Command) -> Self {
+        Self::new(MessageType::Command, command)
+    }
+
+    pub fn hello(hello: &Hello) -> Self {
+        Self::new(MessageType::Hello, hello)
+    }
+
+    pub fn event(event: &Event) -> Self {
+        Self::new(MessageType::Event, event)
+    }
+
+    pub fn query(query: &Query) -> Self {
+        Self::new(MessageType::Query, query)
+    }
+
+    pub fn ping(id: impl Into<String>) -> Self {
+        Self::new(MessageType::Ping, &Value::Null).with_id(id)
+    }
+
+    /// The `pong` answering this `ping`.
+    pub fn pong(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            ..Self::new(MessageType::Pong, &Value::Null)
+        }
+    }
+
+    pub fn goodbye(reason: &str) -> Self {
+        Self::new(
+            MessageType::Goodbye,
+            &serde_json::json!({ "reason": reason }),
+        )
+    }
+
+    pub fn result(id: Option<String>, reply: &Reply) -> Self {
+        Self {
+            id,
+            ..Self::new(MessageType::Result, reply)
+        }
+    }
+
+    fn new(kind: MessageType, payload: &impl Serialize) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            id: None,
+            kind,
+            target: None,
+            payload: serde_json::to_value(payload).unwrap_or(Value::Null),
+        }
+    }
+
+    /// A `result` answering this envelope (same id and target).
+    pub fn reply(&self, reply: &Reply) -> Self {
+        Self::result(self.id.clone(), reply).with_target(self.target.clone())
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_target(mut self, target: Option<String>) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Parses a JSON envelope, or maps a legacy word onto an id-less command.
+    pub fn parse_line(line: &str) -> Result<Self> {
+        let line = line.trim();
+        if !line.starts_with('{') {
+            let word = line
+                .parse::<SocketCommand>()
+                .unwrap_or(SocketCommand::Unknown(line.into()));
+            return match Command::from_legacy(&word) {
+                Some(command) => Ok(Self::command(&command)),
+                None => bail!("unknown command {:?}", line),
+            };
+        }
+        let envelope: Envelope = serde_json::from_str(line).context("Parsing envelope")?;
+        if envelope.v > PROTOCOL_VERSION {
+            bail!(
+                "unsupported protocol version {} (max {})",
+                envelope.v,
+                PROTOCOL_VERSION
+            );
+        }
+        Ok(envelope)
+    }
+
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn as_command(&self) -> Result<Command> {
+        serde_json::from_value(self.payload.clone()).context("Parsing command payload")
+    }
+
+    pub fn as_hello(&self) -> Result<Hello> {
+        serde_json::from_value(self.payload.clone()).context("Parsing hello payload")
+    }
+
+    pub fn as_query(&self) -> Result<Query> {
+        serde_json::from_value(self.payload.clone()).context("Parsing query payload")
+    }
+
+    pub fn as_event(&self) -> Result<Event> {
+        serde_json::from_value(self.payload.clone()).context("Parsing event payload")
+    }
+
+    pub fn as_reply(&self) -> Result<Reply> {
+        serde_json::from_value(self.payload.clone()).context("Parsing result payload")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_words_become_commands() {
+        let env = Envelope::parse_line("dark").unwrap();
+        assert_eq!(env.kind, MessageType::Command);
+        assert_eq!(env.id, None);
+        assert_eq!(
+            env.as_command().unwrap(),
+            Command::Mode {
+                mode: ThemeMode::Dark
+            }
+        );
+        assert!(Envelope::parse_line("bogus").is_err());
+    }
+
+    #[test]
+    fn json_envelope_roundtrip() {
+        let line = r##"{"v":1,"id":"7","type":"command","target":"firefox","payload":{"command":"update","palette":{"colors":["#000000"]}}}"##;
+        let env = Envelope::parse_line(line).unwrap();
+        assert_eq!(env.target.as_deref(), Some("firefox"));
+        assert_eq!(
+            env.as_command().unwrap(),
+            Command::Update {
+                palette: Some(Palette {
+                    colors: vec!["#000000".into()],
+                    wallpaper: None
+                })
+            }
+        );
+        assert_eq!(Envelope::parse_line(&env.to_line()).unwrap(), env);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let line = r#"{"v":99,"type":"command","payload":{"command":"update"}}"#;
+        assert!(Envelope::parse_line(line).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::protocol::socket::{Envelope, Reply};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+/// A command with an id that is waiting for results from the hosts it was relayed to.
+struct Pending {
+    /// Clients waiting for the merged result, with the id each of them used; several when
+    /// identical commands were coalesced.
+    waiters: Vec<(u64, String)>,
+    waiting: HashSet<u64>,
+    results: Vec<Reply>,
+    /// When hosts that have not answered yet are reported as timed out.
+    deadline: Instant,
+}
+
+/// Collects the per-host results of acknowledged commands and merges them into one result for
+/// the client that sent the command.
+///
+/// Commands are relayed under a server-side key instead of the sender's id, so two clients
+/// using the same id cannot receive each other's results.
+#[derive(Default)]
+pub struct Acks {
+    next: u64,
+    pending: HashMap<String, Pending>,
+}
+
+/// A merged result and the client it must be delivered to.
+pub type Delivery = (u64, Envelope);
+
+impl Acks {
+    /// Registers a command relayed to `recipients` and returns the key to relay it under, or the
+    /// final results right away when there is nobody to wait for.
+    pub fn start(
+        &mut self,
+        waiters: Vec<(u64, String)>,
+        recipients: impl IntoIterator<Item = u64>,
+        deadline: Instant,
+    ) -> Result<String, Vec<Delivery>> {
+        let waiting: HashSet<u64> = recipients.into_iter().collect();
+        if waiting.is_empty() {
+            let reply = Reply::error("no browser is connected");
+            return Err(waiters
+                .into_iter()
+                .map(|(origin, id)| (origin, Envelope::result(Some(id), &reply)))
+                .collect());
+        }
+        self.next += 1;
+        let key = format!("walrusfox-{}", self.next);
+        self.pending.insert(
+            key.clone(),
+            Pending {
+                waiters,
+                waiting,
+                results: Vec::new(),
+                deadline,
+            },
+        );
+        Ok(key)
+    }
+
+    /// Records the result `from` sent for `key`; returns the merged results once all are in.
+    pub fn record(&mut self, from: u64, key: &str, reply: Reply) -> Vec<Delivery> {
+        let Some(pending) = self.pending.get_mut(key) else {
+            return Vec::new();
+        };
+        if !pending.waiting.remove(&from) {
+            return Vec::new();
+        }
+        pending.results.push(reply);
+        if pending.waiting.is_empty() {
+            return self.finish(key);
+        }
+        Vec::new()
+    }
+
+    /// The earliest deadline of any pending command.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|p| p.deadline).min()
+    }
+
+    /// Keys of pending commands whose deadline has passed.
+    pub fn overdue(&self, now: Instant) -> Vec<String> {
+        self.pending
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Gives up waiting for `key`; hosts that did not answer are reported as timed out and
+    /// returned alongside the merged result.
+    pub fn expire(&mut self, key: &str) -> Option<(Vec<Delivery>, Vec<u64>)> {
+        let pending = self.pending.get_mut(key)?;
+        let silent: Vec<u64> = pending.waiting.drain().collect();
+        for client in &silent {
+            let reply = Reply::error("timed out").with_host(format!("client {client}"));
+            pending.results.push(reply);
+        }
+        Some((self.finish(key), silent))
+    }
+
+    /// Forgets commands sent by a disconnected client and stops waiting for it as a host.
+    pub fn drop_client(&mut self, client: u64) -> Vec<Delivery> {
+        for pending in self.pending.values_mut() {
+            pending.waiters.retain(|(origin, _)| *origin != client);
+        }
+        self.pending.retain(|_, p| !p.waiters.is_empty());
+        let mut done = Vec::new();
+        for (key, pending) in self.pending.iter_mut() {
+            if pending.waiting.remove(&client) {
+                let reply = Reply::error("disconnected").with_host(format!("client {client}"));
+                pending.results.push(reply);
+                if pending.waiting.is_empty() {
+                    done.push(key.clone());
+                }
+            }
+        }
+        done.iter().flat_map(|key| self.finish(key)).collect()
+    }
+
+    /// Answers every waiting client with `error` and forgets all pending commands.
+    pub fn cancel_all(&mut self, error: &str) -> Vec<Delivery> {
+        let reply = Reply::error(error);
+        self.pending
+            .drain()
+            .flat_map(|(_, pending)| pending.waiters)
+            .map(|(origin, id)| (origin, Envelope::result(Some(id), &reply)))
+            .collect()
+    }
+
+    fn finish(&mut self, key: &str) -> Vec<Delivery> {
+        let Some(pending) = self.pending.remove(key) else {
+            return Vec::new();
+        };
+        let applied: Vec<&Reply> = pending.results.iter().filter(|r| !r.skipped).collect();
+        let failed = applied.iter().filter(|r| !r.ok).count();
+        let mut reply = if applied.is_empty() {
+            Reply::error("no connected browser matched the target")
+        } else if failed > 0 {
+            Reply::error(format!("{} of {} browser(s) failed", failed, applied.len()))
+        } else {
+            Reply::ok(serde_json::Value::Null)
+        };
+        reply.data = json!({ "results": pending.results });
+        pending
+            .waiters
+            .into_iter()
+            .map(|(origin, id)| (origin, Envelope::result(Some(id), &reply)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_results_from_all_hosts() {
+        let mut acks = Acks::default();
+        let now = Instant::now();
+        let waiters = vec![(0, "a".to_string()), (4, "x".to_string())];
+        let key = acks.start(waiters, [1, 2, 3], now).unwrap();
+        assert!(acks
+            .record(1, &key, Reply::ok(Default::default()).with_host("firefox"))
+            .is_empty());
+        assert!(acks.record(2, &key, Reply::skipped()).is_empty());
+        let done = acks.drop_client(3);
+        assert_eq!(done.len(), 2);
+        let (origin, envelope) = &done[0];
+        assert_eq!(*origin, 0);
+        assert_eq!(envelope.id.as_deref(), Some("a"));
+        let reply = envelope.as_reply().unwrap();
+        assert!(!reply.ok);
+        assert_eq!(reply.results().len(), 3);
+        assert_eq!(done[1].1.id.as_deref(), Some("x"));
+
+        assert_eq!(
+            acks.start(vec![(0, "b".into())], [], now)
+                .unwrap_err()
+                .len(),
+            1
+        );
+        let key = acks.start(vec![(0, "c".into())], [1], now).unwrap();
+        assert_eq!(acks.next_deadline(), Some(now));
+        assert_eq!(acks.overdue(now), vec![key.clone()]);
+        let (done, silent) = acks.expire(&key).unwrap();
+        assert_eq!(silent, vec![1]);
+        assert_eq!(
+            done[0].1.as_reply().unwrap().results()[0].error.as_deref(),
+            Some("timed out")
+        );
+    }
+}
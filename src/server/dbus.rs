@@ -0,0 +1,371 @@
+use crate::client::Client;
+use crate::config::Config;
+use crate::protocol::socket::{
+    ClientInfo, Command, Envelope, Event, Hello, MessageType, Palette, Query, Reply, Role,
+    ServerStatus, ThemeMode,
+};
+use anyhow::{Context, Result};
+use serde_json::Value as Json;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::thread::{self, JoinHandle};
+use tracing::{debug, info};
+use zbus::blocking::connection::Builder;
+use zbus::blocking::Connection;
+use zbus::message::{Header, Message};
+use zbus::names::ErrorName;
+use zbus::object_server::SignalEmitter;
+
+pub const SERVICE_NAME: &str = "org.walrusfox.Host";
+pub const OBJECT_PATH: &str = "/org/walrusfox/Host";
+
+/// `org.walrusfox.Host` on the session bus. Like the gateway, it relays method calls over the
+/// server's own socket as a controller, and turns the server's events into D-Bus signals.
+pub struct DbusService {
+    bus: Option<Connection>,
+    events: UnixStream,
+    signals: Option<JoinHandle<()>>,
+}
+
+impl DbusService {
+    /// Connects to the bus at `address` and takes the service name; fails if another process
+    /// owns it.
+    pub fn start(config: &Config, address: &str) -> Result<Self> {
+        let host = Host {
+            config: config.clone(),
+        };
+        // Without replacement a second server cannot take the name from a running one.
+        let bus = Builder::address(address)
+            .and_then(|builder| builder.serve_at(OBJECT_PATH, host))
+            .and_then(|builder| builder.name(SERVICE_NAME))
+            .map(|builder| {
+                builder
+                    .allow_name_replacements(false)
+                    .replace_existing_names(false)
+            })
+            .and_then(Builder::build)
+            .map_err(|e| match e {
+                zbus::Error::NameTaken => {
+                    anyhow::anyhow!("{} is already owned on the session bus", SERVICE_NAME)
+                }
+                e => anyhow::Error::new(e).context(format!("connecting to {}", address)),
+            })?;
+
+        let events = Client::new(config).connect()?;
+        let hello = Hello {
+            subscribe: true,
+            ..Hello::default()
+        };
+        writeln!(&events, "{}", Envelope::hello(&hello).to_line())?;
+        let (signal_bus, signal_config) = (bus.clone(), config.clone());
+        let signal_events = events.try_clone()?;
+        let signals = thread::Builder::new()
+            .name("walrusfox-dbus-signals".to_string())
+            .spawn(move || emit_signals(&signal_bus, &signal_config, signal_events))
+            .context("starting the D-Bus signal thread")?;
+        info!(
+            "Serving {} on the session bus as {}",
+            SERVICE_NAME,
+            bus.unique_name().map(|name| name.as_str()).unwrap_or("?")
+        );
+        Ok(Self {
+            bus: Some(bus),
+            events,
+            signals: Some(signals),
+        })
+    }
+}
+
+impl Drop for DbusService {
+    fn drop(&mut self) {
+        let _ = self.events.shutdown(Shutdown::Both);
+        if let Some(signals) = self.signals.take() {
+            let _ = signals.join();
+        }
+        if let Some(bus) = self.bus.take() {
+            let _ = bus.close();
+        }
+    }
+}
+
+/// The exported object. zbus adds `Properties`, `Introspectable` and `Peer` to it.
+struct Host {
+    config: Config,
+}
+
+#[zbus::interface(name = "org.walrusfox.Host")]
+impl Host {
+    /// Re-themes with `colors`, or from the colors file when it is empty.
+    fn update(&self, colors: Vec<String>, wallpaper: String) -> Result<(), CallError> {
+        debug!("D-Bus call Update");
+        let palette = (!colors.is_empty()).then(|| Palette {
+            colors,
+            wallpaper: Some(wallpaper).filter(|w| !w.is_empty()),
+        });
+        relay(
+            &self.client(),
+            Envelope::command(&Command::Update { palette }),
+        )?;
+        Ok(())
+    }
+
+    fn set_mode(&self, mode: &str) -> Result<(), CallError> {
+        debug!("D-Bus call SetMode {}", mode);
+        let mode = serde_json::from_value::<ThemeMode>(Json::from(mode))
+            .map_err(|_| CallError::invalid_args("mode must be dark, light or auto"))?;
+        relay(&self.client(), Envelope::command(&Command::Mode { mode }))?;
+        Ok(())
+    }
+
+    #[zbus(out_args("colors", "wallpaper"))]
+    fn get_palette(&self) -> Result<(Vec<String>, String), CallError> {
+        let palette: Palette = query(&self.client(), Query::Palette)?;
+        Ok((palette.colors, palette.wallpaper.unwrap_or_default()))
+    }
+
+    #[zbus(property)]
+    fn mode(&self) -> zbus::fdo::Result<String> {
+        let status: ServerStatus = query(&self.client(), Query::Status)?;
+        Ok(status.theme.mode.value().to_string())
+    }
+
+    #[zbus(property)]
+    fn connected_browsers(&self) -> zbus::fdo::Result<Vec<String>> {
+        Ok(connected_browsers(&self.client())?)
+    }
+
+    #[zbus(signal)]
+    async fn palette_changed(
+        emitter: &SignalEmitter<'_>,
+        colors: Vec<String>,
+        wallpaper: String,
+    ) -> zbus::Result<()>;
+}
+
+impl Host {
+    fn client(&self) -> Client<'_> {
+        Client::new(&self.config)
+    }
+}
+
+/// The D-Bus error a call is answered with.
+#[derive(Debug)]
+struct CallError {
+    name: &'static str,
+    text: String,
+}
+
+impl CallError {
+    fn invalid_args(text: impl Into<String>) -> Self {
+        Self {
+            name: "org.freedesktop.DBus.Error.InvalidArgs",
+            text: text.into(),
+        }
+    }
+
+    /// The socket side failed, e.g. no browser is connected.
+    fn failed(text: impl Into<String>) -> Self {
+        Self {
+            name: "org.walrusfox.Host.Error.Failed",
+            text: text.into(),
+        }
+    }
+}
+
+impl zbus::DBusError for CallError {
+    fn create_reply(&self, call: &Header<'_>) -> zbus::Result<Message> {
+        Message::error(call, self.name)?.build(&(self.text.as_str(),))
+    }
+
+    fn name(&self) -> ErrorName<'_> {
+        ErrorName::from_static_str_unchecked(self.name)
+    }
+
+    fn description(&self) -> Option<&str> {
+        Some(&self.text)
+    }
+}
+
+/// Property getters can only fail with the standard errors.
+impl From<CallError> for zbus::fdo::Error {
+    fn from(error: CallError) -> Self {
+        zbus::fdo::Error::Failed(error.text)
+    }
+}
+
+/// Sends `envelope` over the socket like any controller would.
+fn relay(client: &Client, envelope: Envelope) -> Result<Reply, CallError> {
+    match client.request(envelope) {
+        Ok(reply) if reply.ok => Ok(reply),
+        Ok(reply) => Err(CallError::failed(
+            reply.error.unwrap_or_else(|| "command failed".into()),
+        )),
+        Err(e) => Err(CallError::failed(format!("{e:#}"))),
+    }
+}
+
+/// Decodes the `data` of a query result.
+fn query<T: serde::de::DeserializeOwned>(client: &Client, query: Query) -> Result<T, CallError> {
+    let reply = relay(client, Envelope::query(&query))?;
+    serde_json::from_value(reply.data).map_err(|e| CallError::failed(e.to_string()))
+}
+
+fn connected_browsers(client: &Client) -> Result<Vec<String>, CallError> {
+    let clients: Vec<ClientInfo> = query(client, Query::Clients)?;
+    Ok(clients
+        .iter()
+        .filter(|c| c.role == Role::Host)
+        .map(ClientInfo::label)
+        .collect())
+}
+
+/// Turns server events into `PaletteChanged` and `PropertiesChanged` signals. Only commands
+/// sent to every browser count, as targeted ones do not change the shared theme.
+fn emit_signals(bus: &Connection, config: &Config, events: UnixStream) {
+    let client = Client::new(config);
+    let host = match bus.object_server().interface::<_, Host>(OBJECT_PATH) {
+        Ok(host) => host,
+        Err(e) => {
+            debug!("D-Bus object is gone: {}", e);
+            return;
+        }
+    };
+    let emitter = host.signal_emitter();
+    for line in BufReader::new(&events).lines() {
+        let Ok(line) = line else { break };
+        let Ok(envelope) = Envelope::parse_line(&line) else {
+            continue;
+        };
+        if envelope.kind == MessageType::Ping {
+            let _ = writeln!(&events, "{}", envelope.pong().to_line());
+            continue;
+        }
+        if envelope.kind != MessageType::Event {
+            continue;
+        }
+        let sent = match serde_json::from_value::<Event>(envelope.payload) {
+            Ok(Event::Command {
+                command: Command::Update { palette },
+                target: None,
+            }) => {
+                let palette = match palette {
+                    Some(palette) => palette,
+                    None => match query::<Palette>(&client, Query::Palette) {
+                        Ok(palette) => palette,
+                        Err(_) => continue,
+                    },
+                };
+                let wallpaper = palette.wallpaper.unwrap_or_default();
+                zbus::block_on(Host::palette_changed(emitter, palette.colors, wallpaper))
+            }
+            Ok(Event::Command {
+                command: Command::Mode { .. },
+                target: None,
+            }) => zbus::block_on(host.get().mode_changed(emitter)),
+            Ok(Event::HostConnected { .. } | Event::HostDisconnected { .. }) => {
+                zbus::block_on(host.get().connected_browsers_changed(emitter))
+            }
+            _ => continue,
+        };
+        if let Err(e) = sent {
+            debug!("Cannot emit a D-Bus signal: {}", e);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Server, Shutdown};
+    use std::process::{Command as Process, Stdio};
+    use std::time::Duration;
+    use zbus::zvariant::{DynamicType, OwnedValue};
+
+    fn call(
+        bus: &Connection,
+        interface: &str,
+        member: &str,
+        body: &(impl serde::Serialize + DynamicType),
+    ) -> zbus::Result<Message> {
+        bus.call_method(
+            Some(SERVICE_NAME),
+            OBJECT_PATH,
+            Some(interface),
+            member,
+            body,
+        )
+    }
+
+    fn method_error(reply: zbus::Result<Message>) -> (String, String) {
+        match reply {
+            Err(zbus::Error::MethodError(name, text, _)) => {
+                (name.to_string(), text.unwrap_or_default())
+            }
+            other => panic!("expected a D-Bus error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn serves_calls_on_a_private_bus() {
+        let Ok(mut daemon) = Process::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            eprintln!("dbus-daemon is not installed; skipping");
+            return;
+        };
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("walrusfox-dbus-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            socket_file: dir.join("walrusfox.sock"),
+            ..Config::default()
+        };
+        let shutdown = Shutdown::new();
+        let server = Server::new(&config).with_shutdown(shutdown.clone());
+        thread::scope(|scope| {
+            let running = scope.spawn(|| server.init());
+            while UnixStream::connect(&config.socket_file).is_err() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            let service = DbusService::start(&config, address.trim()).unwrap();
+            let taken = DbusService::start(&config, address.trim()).err().unwrap();
+            assert!(taken.to_string().contains("already owned"));
+            let bus = Builder::address(address.trim()).unwrap().build().unwrap();
+
+            let properties = "org.freedesktop.DBus.Properties";
+            let mode = call(&bus, properties, "Get", &(SERVICE_NAME, "Mode")).unwrap();
+            let mode: OwnedValue = mode.body().deserialize().unwrap();
+            assert_eq!(String::try_from(mode).unwrap(), "auto");
+            let (name, _) = method_error(call(&bus, SERVICE_NAME, "SetMode", &("dusk",)));
+            assert_eq!(name, "org.freedesktop.DBus.Error.InvalidArgs");
+            let failed = method_error(call(&bus, SERVICE_NAME, "SetMode", &("dark",)));
+            assert_eq!(
+                failed,
+                (
+                    "org.walrusfox.Host.Error.Failed".to_string(),
+                    "no browser is connected".to_string()
+                )
+            );
+            let introspectable = "org.freedesktop.DBus.Introspectable";
+            let xml = call(&bus, introspectable, "Introspect", &()).unwrap();
+            let xml: String = xml.body().deserialize().unwrap();
+            assert!(xml.contains("PaletteChanged"));
+
+            drop(service);
+            shutdown.trigger();
+            running.join().unwrap().unwrap();
+        });
+        let _ = daemon.kill();
+        let _ = daemon.wait();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
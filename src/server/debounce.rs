@@ -0,0 +1,212 @@
+use crate::protocol::socket::Command;
+use std::time::{Duration, Instant};
+
+/// A command ready to be relayed, with the clients that sent it and those waiting for its
+/// result (`(client, id)`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub command: Command,
+    pub target: Option<String>,
+    pub senders: Vec<u64>,
+    pub waiters: Vec<(u64, String)>,
+}
+
+impl Batch {
+    fn same_command(&self, other: &Batch) -> bool {
+        self.command == other.command && self.target == other.target
+    }
+}
+
+/// A command that was relayed recently; repeats within the window are merged into `trailing`.
+struct Window {
+    batch: Batch,
+    until: Instant,
+    trailing: Option<Batch>,
+}
+
+/// Coalesces bursts of identical idempotent commands.
+///
+/// The first command of a burst is relayed at once; identical commands arriving within the
+/// window are merged into one trailing send at the end of it, so the last state (e.g. a colors
+/// file rewritten mid-burst) still reaches the browsers. Any other command first flushes pending
+/// trailing sends, which keeps the relative order of different commands.
+pub struct Debouncer {
+    window: Duration,
+    windows: Vec<Window>,
+}
+
+impl Debouncer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            windows: Vec::new(),
+        }
+    }
+
+    /// Returns the batches to relay now, in order.
+    pub fn submit(&mut self, batch: Batch, now: Instant) -> Vec<Batch> {
+        let mut out = self.due(now);
+        self.windows.retain(|w| w.until > now);
+        if self.window.is_zero() || !batch.command.is_idempotent() {
+            out.extend(self.flush_all());
+            out.push(batch);
+            return out;
+        }
+
+        for window in &mut self.windows {
+            if !window.batch.same_command(&batch) {
+                out.extend(window.trailing.take());
+            }
+        }
+        if let Some(window) = self
+            .windows
+            .iter_mut()
+            .find(|w| w.batch.same_command(&batch) && w.until > now)
+        {
+            match &mut window.trailing {
+                Some(trailing) => {
+                    trailing.senders.extend(batch.senders);
+                    trailing.waiters.extend(batch.waiters);
+                }
+                None => window.trailing = Some(batch),
+            }
+            return out;
+        }
+
+        self.windows.retain(|w| !w.batch.same_command(&batch));
+        self.windows.push(Window {
+            batch: Batch {
+                senders: Vec::new(),
+                waiters: Vec::new(),
+                ..batch.clone()
+            },
+            until: now + self.window,
+            trailing: None,
+        });
+        out.push(batch);
+        out
+    }
+
+    /// When the next trailing send is due, if any.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.windows
+            .iter()
+            .filter(|w| w.trailing.is_some())
+            .map(|w| w.until)
+            .min()
+    }
+
+    /// Trailing sends whose window has ended; each one opens a new window.
+    pub fn due(&mut self, now: Instant) -> Vec<Batch> {
+        let mut out = Vec::new();
+        for window in &mut self.windows {
+            if window.until <= now {
+                if let Some(trailing) = window.trailing.take() {
+                    window.until = now + self.window;
+                    out.push(trailing);
+                }
+            }
+        }
+        out
+    }
+
+    /// Every pending trailing send, regardless of its window.
+    pub fn flush_all(&mut self) -> Vec<Batch> {
+        self.windows
+            .iter_mut()
+            .filter_map(|w| w.trailing.take())
+            .collect()
+    }
+}
+
+/// Per-client rate limit: `rate` commands per second with bursts of up to `rate`.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A rate of 0 disables the limit.
+    pub fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            last: now,
+        }
+    }
+
+    pub fn take(&mut self, now: Instant) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::socket::ThemeMode;
+
+    fn batch(command: Command, waiter: &str) -> Batch {
+        Batch {
+            command,
+            target: None,
+            senders: vec![1],
+            waiters: vec![(1, waiter.to_string())],
+        }
+    }
+
+    #[test]
+    fn coalesces_bursts_and_keeps_order() {
+        let update = Command::Update { palette: None };
+        let dark = Command::Mode {
+            mode: ThemeMode::Dark,
+        };
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let mut debouncer = Debouncer::new(ms(100));
+
+        assert_eq!(debouncer.submit(batch(update.clone(), "a"), t0).len(), 1);
+        assert!(debouncer
+            .submit(batch(update.clone(), "b"), t0 + ms(10))
+            .is_empty());
+        assert!(debouncer
+            .submit(batch(update.clone(), "c"), t0 + ms(20))
+            .is_empty());
+        assert_eq!(debouncer.next_due(), Some(t0 + ms(100)));
+        assert!(debouncer.due(t0 + ms(50)).is_empty());
+        let trailing = debouncer.due(t0 + ms(100));
+        assert_eq!(trailing.len(), 1);
+        assert_eq!(trailing[0].waiters.len(), 2);
+
+        // A different command flushes the pending trailing update before it.
+        assert!(debouncer
+            .submit(batch(update.clone(), "d"), t0 + ms(150))
+            .is_empty());
+        let out = debouncer.submit(batch(dark.clone(), "e"), t0 + ms(160));
+        let commands: Vec<&Command> = out.iter().map(|b| &b.command).collect();
+        assert_eq!(commands, vec![&update, &dark]);
+        assert_eq!(debouncer.next_due(), None);
+    }
+
+    #[test]
+    fn token_bucket_limits_rate() {
+        let t0 = Instant::now();
+        let mut bucket = TokenBucket::new(2, t0);
+        assert!(bucket.take(t0));
+        assert!(bucket.take(t0));
+        assert!(!bucket.take(t0));
+        assert!(bucket.take(t0 + Duration::from_millis(500)));
+        assert!(TokenBucket::new(0, t0).take(t0));
+    }
+}
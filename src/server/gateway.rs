@@ -0,0 +1,615 @@
+use crate::client::Client;
+use crate::config::{Config, MAX_MSG_LEN, WRITE_TIMEOUT};
+use crate::protocol::socket::{Command, Envelope, Hello, MessageType, Palette, Query, ThemeMode};
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, Shutdown as NetShutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::{debug, info};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, Role, WebSocketConfig};
+use tungstenite::{Message, WebSocket};
+
+const MAX_CONNECTIONS: usize = 32; // requests and event streams served at once
+const MAX_HEAD_LEN: usize = 8 * 1024; // request line and headers
+const MAX_HEADERS: usize = 32;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const EVENT_POLL: Duration = Duration::from_millis(50); // how long a WebSocket read waits for frames
+
+/// Loopback HTTP/WebSocket front end for tools that cannot talk to a Unix socket, e.g. status
+/// bar widgets. Requests are relayed over the server's own socket as a controller, so they take
+/// the same path (rate limit, debouncing, acks) as `walrusfox update`.
+pub struct Gateway {
+    addr: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    config: Config,
+    token: String,
+    active: AtomicUsize,
+}
+
+impl Gateway {
+    /// Listens on `127.0.0.1:port` (0 picks a free port); requests must carry the token stored
+    /// in `token_file`, which is created on first use.
+    pub fn start(config: &Config, port: u16, token_file: &Path) -> Result<Self> {
+        let token = load_token(token_file)?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .with_context(|| format!("binding the gateway to 127.0.0.1:{}", port))?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            config: config.clone(),
+            token,
+            active: AtomicUsize::new(0),
+        });
+        let stopping = Arc::new(AtomicBool::new(false));
+        let stop = stopping.clone();
+        let handle = thread::Builder::new()
+            .name("walrusfox-gateway".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => shared.clone().accept(stream),
+                        Err(e) => debug!("Gateway accept failed: {}", e),
+                    }
+                }
+            })
+            .context("starting the gateway thread")?;
+        info!(
+            "Gateway listening on http://{} (token in {})",
+            addr,
+            token_file.display()
+        );
+        Ok(Self {
+            addr,
+            stopping,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Gateway {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // Wakes the blocking accept so the thread sees the flag.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Shared {
+    fn accept(self: Arc<Self>, stream: TcpStream) {
+        if self.active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            let _ = Response::error(503, "too many connections").write(&stream);
+            return;
+        }
+        let shared = self.clone();
+        let spawned = thread::Builder::new()
+            .name("walrusfox-gateway-conn".to_string())
+            .spawn(move || {
+                if let Err(e) = shared.serve(stream) {
+                    debug!("Gateway connection failed: {:#}", e);
+                }
+                shared.active.fetch_sub(1, Ordering::SeqCst);
+            });
+        if spawned.is_err() {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn serve(&self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let (request, rest) = match Request::read(&stream) {
+            Ok(read) => read,
+            Err(response) => return response.write(&stream),
+        };
+        let events = request.method == "GET" && request.path == "/events" && request.is_upgrade();
+        if !request.authorized(&self.token, events) {
+            return Response::error(401, "missing or wrong token").write(&stream);
+        }
+        let client = Client::new(&self.config);
+        if events {
+            if !request.origin_allowed() {
+                return Response::error(403, "origin not allowed").write(&stream);
+            }
+            return stream_events(stream, rest, &request, &client);
+        }
+        route(&request, &client).write(&stream)
+    }
+}
+
+fn route(request: &Request, client: &Client) -> Response {
+    let target = request.param("target").map(str::to_string);
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/update") => {
+            let palette = if request.body.trim_ascii().is_empty() {
+                None
+            } else {
+                match serde_json::from_slice::<Palette>(&request.body) {
+                    Ok(palette) => Some(palette),
+                    Err(e) => return Response::error(400, &format!("invalid palette: {e}")),
+                }
+            };
+            let command = Command::Update { palette };
+            forward(client, Envelope::command(&command).with_target(target))
+        }
+        ("POST", path) if path.starts_with("/mode/") => {
+            let mode = &path["/mode/".len()..];
+            match serde_json::from_value::<ThemeMode>(Value::from(mode)) {
+                Ok(mode) => {
+                    let command = Command::Mode { mode };
+                    forward(client, Envelope::command(&command).with_target(target))
+                }
+                Err(_) => Response::error(404, "mode must be dark, light or auto"),
+            }
+        }
+        ("GET", "/status") => forward(client, Envelope::query(&Query::Status)),
+        ("GET", "/palette") => forward(client, Envelope::query(&Query::Palette)),
+        ("GET", "/events") => Response::error(426, "events are streamed over a WebSocket"),
+        (_, "/update" | "/status" | "/palette" | "/events") => {
+            Response::error(405, "method not allowed")
+        }
+        (_, path) if path.starts_with("/mode/") => Response::error(405, "method not allowed"),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+/// Relays `envelope` and answers with the server's `result`: 200 when it succeeded, 502 when
+/// the server or the browsers reported an error and 503 when the server cannot be reached.
+fn forward(client: &Client, envelope: Envelope) -> Response {
+    match client.request(envelope) {
+        Ok(reply) => Response {
+            status: if reply.ok { 200 } else { 502 },
+            body: serde_json::to_value(&reply).unwrap_or_default(),
+        },
+        Err(e) => Response::error(503, &format!("{e:#}")),
+    }
+}
+
+/// Upgrades to a WebSocket and sends every server event as a text message until either side
+/// closes. `rest` holds what the browser sent after the request head, i.e. its first frames.
+///
+/// Server lines are read on a thread of their own and handed over through a channel, so this
+/// thread alone owns the WebSocket, polling it for frames in between.
+fn stream_events(
+    stream: TcpStream,
+    rest: Vec<u8>,
+    request: &Request,
+    client: &Client,
+) -> Result<()> {
+    let Some(key) = request.header("sec-websocket-key") else {
+        return Response::error(400, "missing Sec-WebSocket-Key").write(&stream);
+    };
+    let server = match client.connect() {
+        Ok(server) => server,
+        Err(e) => return Response::error(503, &format!("{e:#}")).write(&stream),
+    };
+    let hello = Hello {
+        subscribe: true,
+        ..Hello::default()
+    };
+    writeln!(&server, "{}", Envelope::hello(&hello).to_line())?;
+    write!(
+        &stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    )?;
+
+    let (events, received) = mpsc::channel::<String>();
+    let reader = {
+        let server = server.try_clone()?;
+        thread::Builder::new()
+            .name("walrusfox-gateway-ws".to_string())
+            .spawn(move || {
+                for line in BufReader::new(&server).lines() {
+                    let Ok(line) = line else { break };
+                    match Envelope::parse_line(&line) {
+                        Ok(env) if env.kind == MessageType::Event => {
+                            let sent = events.send(env.payload.to_string());
+                            if sent.is_err() {
+                                break; // the WebSocket is gone
+                            }
+                        }
+                        Ok(env) if env.kind == MessageType::Ping => {
+                            let _ = writeln!(&server, "{}", env.pong().to_line());
+                        }
+                        _ => {}
+                    }
+                }
+            })?
+    };
+
+    stream.set_read_timeout(Some(EVENT_POLL))?;
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(MAX_MSG_LEN))
+        .max_frame_size(Some(MAX_MSG_LEN));
+    let mut ws = WebSocket::from_partially_read(stream, rest, Role::Server, Some(config));
+    let server_gone = 'stream: loop {
+        loop {
+            match received.try_recv() {
+                Ok(event) => {
+                    if ws.send(Message::text(event)).is_err() {
+                        break 'stream false;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => break 'stream true,
+            }
+        }
+        // Pings are answered (and a close echoed) by tungstenite while reading.
+        match ws.read() {
+            Ok(Message::Close(_)) => break false,
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(_) => break false,
+        }
+    };
+    if server_gone {
+        // 1001 tells the browser the server went away.
+        let _ = ws.close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "".into(),
+        }));
+    }
+    let _ = ws.flush();
+    let _ = ws.get_ref().shutdown(NetShutdown::Both);
+    // Closing our end of the server connection ends the reader.
+    let _ = server.shutdown(NetShutdown::Both);
+    let _ = reader.join();
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    params: Vec<(String, String)>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    /// Reads one request and returns it with whatever arrived after it. What cannot be served
+    /// is returned as the error response to send.
+    fn read(mut stream: &TcpStream) -> std::result::Result<(Self, Vec<u8>), Response> {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        let (mut request, head_len) = loop {
+            if let Some(parsed) = Self::parse_head(&buf)? {
+                break parsed;
+            }
+            if buf.len() >= MAX_HEAD_LEN {
+                return Err(Response::error(431, "request headers too large"));
+            }
+            match stream.read(&mut chunk).map_err(io_error)? {
+                0 => return Err(Response::error(400, "incomplete request")),
+                n => buf.extend_from_slice(&chunk[..n]),
+            }
+        };
+        let mut rest = buf.split_off(head_len);
+
+        let len = match request.header("content-length").map(str::parse::<usize>) {
+            None => 0,
+            Some(Ok(len)) if len <= MAX_MSG_LEN => len,
+            Some(Ok(_)) => return Err(Response::error(413, "request body too large")),
+            Some(Err(_)) => return Err(Response::error(400, "invalid Content-Length")),
+        };
+        while rest.len() < len {
+            match stream.read(&mut chunk).map_err(io_error)? {
+                0 => return Err(Response::error(400, "incomplete request")),
+                n => rest.extend_from_slice(&chunk[..n]),
+            }
+        }
+        let after = rest.split_off(len);
+        request.body = rest;
+        Ok((request, after))
+    }
+
+    /// The request and the length of its head once `buf` holds all of it.
+    fn parse_head(buf: &[u8]) -> std::result::Result<Option<(Self, usize)>, Response> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut headers);
+        let head_len = match parsed.parse(buf) {
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) => return Ok(None),
+            Err(httparse::Error::TooManyHeaders) => {
+                return Err(Response::error(431, "too many request headers"))
+            }
+            Err(_) => return Err(Response::error(400, "malformed request")),
+        };
+        let target = parsed.path.unwrap_or_default();
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let request = Self {
+            method: parsed.method.unwrap_or_default().to_string(),
+            path: path.to_string(),
+            params: form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect(),
+            headers: parsed
+                .headers
+                .iter()
+                .map(|h| {
+                    let value = String::from_utf8_lossy(h.value).trim().to_string();
+                    (h.name.to_ascii_lowercase(), value)
+                })
+                .collect(),
+            body: Vec::new(),
+        };
+        Ok(Some((request, head_len)))
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn is_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+    }
+
+    /// Accepts `Authorization: Bearer <token>`. `?token=` is accepted only on the `/events`
+    /// upgrade, as browsers cannot set headers on WebSocket requests; elsewhere it would end up
+    /// in shell histories and logs for nothing.
+    fn authorized(&self, token: &str, events: bool) -> bool {
+        let given = self
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+            .or_else(|| self.param("token").filter(|_| events));
+        given.is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+    }
+
+    /// Browsers send `Origin` with every WebSocket request; only pages served from this machine
+    /// may open the stream. Tools that send none are let through, the token guards them.
+    fn origin_allowed(&self) -> bool {
+        let Some(origin) = self.header("origin") else {
+            return true;
+        };
+        let Some((scheme, authority)) = origin.split_once("://") else {
+            return false;
+        };
+        let host = match authority.strip_prefix('[') {
+            Some(v6) => v6.split(']').next().unwrap_or_default(),
+            None => authority.split(':').next().unwrap_or_default(),
+        };
+        matches!(scheme, "http" | "https") && matches!(host, "127.0.0.1" | "localhost" | "::1")
+    }
+}
+
+struct Response {
+    status: u16,
+    body: Value,
+}
+
+impl Response {
+    fn error(status: u16, error: &str) -> Self {
+        Self {
+            status,
+            body: json!({ "ok": false, "error": error }),
+        }
+    }
+
+    fn write(&self, mut stream: &TcpStream) -> Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            426 => "Upgrade Required",
+            431 => "Request Header Fields Too Large",
+            502 => "Bad Gateway",
+            _ => "Service Unavailable",
+        };
+        let body = self.body.to_string();
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            self.status,
+            reason,
+            body.len(),
+            body
+        )?;
+        Ok(())
+    }
+}
+
+fn io_error(e: io::Error) -> Response {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            Response::error(408, "request timeout")
+        }
+        _ => Response::error(400, "unreadable request"),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Reads the token from `path`, creating the file (mode 0600) with a random token on first use.
+/// A token file other users can read is refused rather than trusted.
+fn load_token(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(token) if !token.trim().is_empty() => {
+            let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                bail!(
+                    "{} is accessible to other users (mode {:o}); run `chmod 600` on it",
+                    path.display(),
+                    mode
+                );
+            }
+            return Ok(token.trim().to_string());
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    }
+
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .context("reading /dev/urandom")?;
+    let token: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("creating {}", path.display()))?;
+    // An existing empty file keeps its mode on open.
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    writeln!(file, "{}", token).with_context(|| format!("writing {}", path.display()))?;
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Server, Shutdown};
+    use std::os::unix::net::UnixStream;
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::HandshakeError;
+
+    fn get(addr: SocketAddr, request: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn serves_authorized_requests_only() {
+        let dir = std::env::temp_dir().join(format!("walrusfox-gateway-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            socket_file: dir.join("walrusfox.sock"),
+            ..Config::default()
+        };
+        let token_file = dir.join("gateway-token");
+        let shutdown = Shutdown::new();
+        let server = Server::new(&config).with_shutdown(shutdown.clone());
+        thread::scope(|scope| {
+            let running = scope.spawn(|| server.init());
+            while UnixStream::connect(&config.socket_file).is_err() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            let gateway = Gateway::start(&config, 0, &token_file).unwrap();
+            let mode = fs::metadata(&token_file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            let token = fs::read_to_string(&token_file).unwrap();
+            let token = token.trim();
+
+            let (status, _) = get(gateway.addr(), "GET /status HTTP/1.1\r\n\r\n");
+            assert_eq!(status, 401);
+            let (status, body) = get(
+                gateway.addr(),
+                &format!("GET /status HTTP/1.1\r\nAuthorization: Bearer {token}\r\n\r\n"),
+            );
+            assert_eq!(status, 200);
+            assert_eq!(body["data"]["pid"], std::process::id());
+            let bearer = format!("Authorization: Bearer {token}");
+            let (status, body) = get(
+                gateway.addr(),
+                &format!("POST /mode/dark HTTP/1.1\r\n{bearer}\r\n\r\n"),
+            );
+            assert_eq!(status, 502);
+            assert_eq!(body["error"], "no browser is connected");
+            let (status, _) = get(
+                gateway.addr(),
+                &format!("POST /mode/dusk HTTP/1.1\r\n{bearer}\r\n\r\n"),
+            );
+            assert_eq!(status, 404);
+            // The query token is only for the WebSocket upgrade.
+            let (status, _) = get(
+                gateway.addr(),
+                &format!("GET /status?token={token} HTTP/1.1\r\n\r\n"),
+            );
+            assert_eq!(status, 401);
+            // Only a GET is upgraded; anything else is routed like a plain request.
+            let upgrade = "Connection: Upgrade\r\nUpgrade: websocket\r\n\
+                Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==";
+            let (status, _) = get(
+                gateway.addr(),
+                &format!("POST /events HTTP/1.1\r\n{bearer}\r\n{upgrade}\r\n\r\n"),
+            );
+            assert_eq!(status, 405);
+
+            let url = format!("ws://{}/events?token={token}", gateway.addr());
+            let mut foreign = url.as_str().into_client_request().unwrap();
+            foreign
+                .headers_mut()
+                .insert("Origin", "https://example.com".parse().unwrap());
+            let stream = TcpStream::connect(gateway.addr()).unwrap();
+            match tungstenite::client(foreign, stream) {
+                Err(HandshakeError::Failure(tungstenite::Error::Http(response))) => {
+                    assert_eq!(response.status(), 403)
+                }
+                other => panic!("foreign origin was not refused: {:?}", other.map(|r| r.1)),
+            }
+            let mut local = url.as_str().into_client_request().unwrap();
+            local
+                .headers_mut()
+                .insert("Origin", "http://localhost:8080".parse().unwrap());
+            let stream = TcpStream::connect(gateway.addr()).unwrap();
+            let (mut ws, _) = tungstenite::client(local, stream).unwrap();
+            ws.send(Message::Ping("hi".into())).unwrap();
+            assert_eq!(ws.read().unwrap(), Message::Pong("hi".into()));
+            ws.close(None).unwrap();
+            while ws.read().is_ok() {}
+
+            drop(gateway);
+            shutdown.trigger();
+            running.join().unwrap().unwrap();
+        });
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
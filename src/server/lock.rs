@@ -0,0 +1,113 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Exclusive open file description lock (`F_OFD_SETLK`) on `<socket>.lock`, held for as long as
+/// the server runs. Unlike `flock`, it can be tested with `F_OFD_GETLK` without taking it.
+///
+/// Only the holder may create or remove the socket file, so two servers starting at once can
+/// no longer unlink each other's live socket. The lock file itself stays in place: removing it
+/// would let a third process lock a fresh inode while the second still waits on the old one.
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+}
+
+impl InstanceLock {
+    pub fn path_for(socket: &Path) -> PathBuf {
+        let mut name = socket.as_os_str().to_owned();
+        name.push(".lock");
+        PathBuf::from(name)
+    }
+
+    /// Whether a server holds the lock for `socket`, i.e. is running or starting up. Only asks
+    /// the kernel, so a server taking the lock at the same moment is never turned away.
+    pub fn is_held(socket: &Path) -> bool {
+        let Ok(file) = File::open(Self::path_for(socket)) else {
+            return false;
+        };
+        let mut lock = Self::whole_file(libc::F_WRLCK);
+        // SAFETY: `lock` is a valid flock record; the descriptor stays open for the call.
+        let rc = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_GETLK, &mut lock) };
+        rc == 0 && lock.l_type != libc::F_UNLCK as libc::c_short
+    }
+
+    /// Takes the lock and records our pid in it, or fails naming the pid of the running server.
+    pub fn acquire(socket: &Path) -> Result<Self> {
+        let path = Self::path_for(socket);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("opening lock file {}", path.display()))?;
+        let lock = Self::whole_file(libc::F_WRLCK);
+        // SAFETY: `lock` is a valid flock record; the descriptor stays open for the call.
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_OFD_SETLK, &lock) } != 0 {
+            let err = io::Error::last_os_error();
+            if matches!(err.raw_os_error(), Some(libc::EAGAIN | libc::EACCES)) {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                match pid.trim() {
+                    "" => bail!("walrusfox server is already running"),
+                    pid => bail!("walrusfox server is already running (pid {})", pid),
+                }
+            }
+            return Err(err).with_context(|| format!("locking {}", path.display()));
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(Self { file })
+    }
+
+    fn whole_file(kind: libc::c_int) -> libc::flock {
+        // SAFETY: all-zero is a valid `flock`: start 0, length 0 (to the end), pid 0 as OFD
+        // locks require.
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = kind as libc::c_short;
+        lock.l_whence = libc::SEEK_SET as libc::c_short;
+        lock
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Leave no pid behind for the next server to report; closing the file releases the lock.
+        let _ = self.file.set_len(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_instance_is_refused_until_the_first_exits() {
+        let dir = std::env::temp_dir().join(format!("walrusfox-lock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("walrusfox.sock");
+
+        assert!(!InstanceLock::is_held(&socket));
+        let first = InstanceLock::acquire(&socket).unwrap();
+        assert!(InstanceLock::is_held(&socket));
+        let err = InstanceLock::acquire(&socket).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "walrusfox server is already running (pid {})",
+                std::process::id()
+            )
+        );
+        drop(first);
+        assert!(!InstanceLock::is_held(&socket));
+        assert!(InstanceLock::acquire(&socket).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
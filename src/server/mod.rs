@@ -0,0 +1,1137 @@
+mod acks;
+mod dbus;
+mod debounce;
+mod gateway;
+mod lock;
+mod peer;
+mod shutdown;
+mod spawn;
+mod state;
+
+pub use dbus::DbusService;
+pub use gateway::Gateway;
+pub use lock::InstanceLock;
+pub use shutdown::Shutdown;
+pub use spawn::{spawn_detached, SERVE_ARG};
+
+use crate::config::{
+    Config, Settings, ACK_TIMEOUT, HEARTBEAT_INTERVAL, MAX_CLIENTS, MAX_MISSED_HEARTBEATS,
+    MAX_MSG_LEN, MAX_QUEUED_BYTES, SHUTDOWN_TIMEOUT, WRITE_TIMEOUT,
+};
+use crate::protocol::socket::{
+    ClientInfo, Command, Envelope, Event, Hello, MessageType, Query, Reply, Role, ServerStatus,
+};
+use crate::utils::systemd::{self, Notifier};
+use crate::utils::time::unix_now;
+use acks::{Acks, Delivery};
+use anyhow::Context;
+use anyhow::{bail, Result};
+use debounce::{Batch, Debouncer, TokenBucket};
+use peer::{PeerCred, PeerPolicy};
+use state::ThemeState;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{remove_file, set_permissions, Permissions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Holds the instance lock and removes the socket file on drop, before the lock is released.
+/// `path` is `None` when systemd owns the socket file.
+struct SocketGuard {
+    path: Option<PathBuf>,
+    _lock: InstanceLock,
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = remove_file(path);
+        }
+    }
+}
+
+/// A connection served by the event loop. The socket is non-blocking; input is split into
+/// lines as it arrives and output is queued until the socket accepts it. Clients that let the
+/// queue overflow or leave it unread for `WRITE_TIMEOUT` are evicted.
+struct Client {
+    stream: UnixStream,
+    /// Role and metadata from the connection's `hello`; a controller until then.
+    info: ClientInfo,
+    bucket: TokenBucket,
+    input: Vec<u8>,
+    /// Set while skipping the rest of an overlong line.
+    discarding: bool,
+    output: Vec<u8>,
+    /// When the queued output last stopped draining.
+    stalled_since: Option<Instant>,
+    /// Set on EOF or a socket error; the client is dropped at the end of the loop iteration.
+    closed: bool,
+    evicted: bool,
+    /// Said `hello`, so it is expected to answer heartbeats.
+    registered: bool,
+    /// The unanswered heartbeat ping and when it was sent.
+    heartbeat: Option<(String, Instant)>,
+    missed_heartbeats: u32,
+}
+
+impl Client {
+    fn new(id: u64, stream: UnixStream, cred: PeerCred, rate: u32) -> Self {
+        let info = ClientInfo {
+            id,
+            pid: Some(cred.pid),
+            uid: Some(cred.uid),
+            connected_at: unix_now(),
+            ..ClientInfo::default()
+        };
+        Self {
+            stream,
+            info,
+            bucket: TokenBucket::new(rate, Instant::now()),
+            input: Vec::new(),
+            discarding: false,
+            output: Vec::new(),
+            stalled_since: None,
+            closed: false,
+            evicted: false,
+            registered: false,
+            heartbeat: None,
+            missed_heartbeats: 0,
+        }
+    }
+
+    fn is_host(&self) -> bool {
+        self.info.role == Role::Host
+    }
+
+    fn record_command(&mut self, command: &Command) {
+        self.info.last_command = Some(command.summary());
+        self.info.last_command_at = Some(unix_now());
+    }
+
+    fn record_error(&mut self) {
+        self.info.errors += 1;
+    }
+
+    /// Queues a line and writes as much as the socket takes right away.
+    fn send(&mut self, line: &str) {
+        if self.closed {
+            return;
+        }
+        if self.output.len() + line.len() + 1 > MAX_QUEUED_BYTES {
+            let queued = self.output.len();
+            self.evict(&format!("outbound queue full ({} bytes unread)", queued));
+            return;
+        }
+        self.output.extend_from_slice(line.as_bytes());
+        self.output.push(b'\n');
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        let queued = self.output.len();
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Write error to client {}: {}", self.info.id, e);
+                    self.output.clear();
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+        if self.output.is_empty() {
+            self.stalled_since = None;
+        } else if self.output.len() < queued || self.stalled_since.is_none() {
+            self.stalled_since = Some(Instant::now());
+        }
+    }
+
+    /// Drops the queued output and disconnects the client at the end of the loop iteration.
+    fn evict(&mut self, reason: &str) {
+        warn!(
+            "Evicting client {} ({}): {}",
+            self.info.id,
+            self.info.label(),
+            reason
+        );
+        self.output.clear();
+        self.stalled_since = None;
+        self.closed = true;
+        self.evicted = true;
+    }
+
+    /// Reads what is available and returns the complete lines and whether the peer closed its
+    /// end; at EOF the unterminated rest counts as a line too.
+    fn read_lines(&mut self) -> (Vec<String>, bool) {
+        let mut buf = [0u8; 8192];
+        let eof = match self.stream.read(&mut buf) {
+            Ok(0) => true,
+            Ok(n) => {
+                self.input.extend_from_slice(&buf[..n]);
+                false
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {
+                return (Vec::new(), false);
+            }
+            Err(e) => {
+                warn!("Error reading from client {}: {}", self.info.id, e);
+                self.closed = true;
+                return (Vec::new(), false);
+            }
+        };
+        if eof && !self.input.is_empty() {
+            self.input.push(b'\n');
+        }
+
+        let mut lines = Vec::new();
+        while let Some(pos) = self.input.iter().position(|b| *b == b'\n') {
+            let mut line: Vec<u8> = self.input.drain(..=pos).collect();
+            if std::mem::take(&mut self.discarding) {
+                continue;
+            }
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            match String::from_utf8(line) {
+                Ok(line) => lines.push(line),
+                Err(e) => {
+                    warn!("Error reading from client {}: {}", self.info.id, e);
+                    self.closed = true;
+                    return (lines, eof);
+                }
+            }
+        }
+        if self.input.len() > MAX_MSG_LEN {
+            warn!(
+                "Ignoring overlong command from client {} (more than {} bytes)",
+                self.info.id, MAX_MSG_LEN
+            );
+            self.input.clear();
+            self.discarding = true;
+        }
+        (lines, eof)
+    }
+}
+
+/// Where a line read from a client goes.
+enum Route {
+    /// To every host; with an id, their results are collected for the sender.
+    Command(Envelope, Command),
+    /// Registers the sender's role.
+    Hello(Option<String>, Hello),
+    /// Answered by the server itself.
+    Query(Option<String>, Query),
+    /// A host's result for an acknowledged command (server-side key).
+    Ack(String, Reply),
+    /// Answered with a `pong`.
+    Ping(Envelope),
+    /// Answers our heartbeat with this id.
+    Pong(Option<String>),
+    /// Nowhere; the sender gets this error result instead.
+    Reject(Envelope),
+    Drop,
+}
+
+pub struct Server<'a> {
+    config: &'a Config,
+    shutdown: Shutdown,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// Lets `shutdown` stop the server; `init` then returns once the clients were told.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn init(&self) -> Result<()> {
+        let path = self.config.socket_file.clone();
+        let mut hub = Hub::new(self.config);
+        let (listener, guard) = Self::bind_socket(&path, hub.policy.group())?;
+        listener
+            .set_nonblocking(true)
+            .context("making the listener non-blocking")?;
+        let (waker, wake) = UnixStream::pair().context("creating the wake-up pair")?;
+        waker.set_nonblocking(true)?;
+        wake.set_nonblocking(true)?;
+        self.shutdown.arm(waker);
+        info!("Server listening on {}", path.display());
+        let gateway = self.start_gateway();
+        let dbus = self.start_dbus();
+
+        if let Some(notifier) = &hub.notifier {
+            notifier.ready();
+        }
+        hub.report_status();
+        let watchdog = hub.notifier.as_ref().and_then(|n| n.watchdog_interval());
+        let mut next_ping = Instant::now();
+
+        while !self.shutdown.is_triggered() {
+            let now = Instant::now();
+            let mut deadline = hub.next_deadline();
+            if let (Some(notifier), Some(interval)) = (&hub.notifier, watchdog) {
+                if now >= next_ping {
+                    notifier.watchdog();
+                    next_ping = now + interval;
+                }
+                deadline = Some(deadline.map_or(next_ping, |d| d.min(next_ping)));
+            }
+            let timeout = deadline.map(|d| d.saturating_duration_since(now));
+            hub.poll(&listener, &wake, timeout)
+                .context("waiting for socket activity")?;
+            hub.run_timers(Instant::now());
+            hub.reap();
+        }
+
+        info!("Shutting down; closing {} connection(s)", hub.clients.len());
+        drop(listener);
+        drop(gateway);
+        drop(dbus);
+        hub.say_goodbye();
+        hub.drain(Instant::now() + SHUTDOWN_TIMEOUT);
+        hub.close_all();
+        // Removes the socket file last, once nobody can be talking to us any more.
+        drop(guard);
+        info!("Server stopped");
+        Ok(())
+    }
+
+    /// Starts the HTTP/WebSocket gateway when `gatewayPort` is configured. It is an add-on: if it
+    /// cannot start, the socket keeps serving the browsers.
+    fn start_gateway(&self) -> Option<Gateway> {
+        let port = self.config.settings.gateway_port?;
+        let started = Settings::gateway_token_path()
+            .context("no config directory for the gateway token")
+            .and_then(|token_file| Gateway::start(self.config, port, &token_file));
+        match started {
+            Ok(gateway) => Some(gateway),
+            Err(e) => {
+                warn!("Gateway disabled: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Takes `org.walrusfox.Host` on the session bus when `dbus` is configured; like the gateway,
+    /// failing to do so leaves the socket serving.
+    fn start_dbus(&self) -> Option<DbusService> {
+        if !self.config.settings.dbus {
+            return None;
+        }
+        let started = env::var("DBUS_SESSION_BUS_ADDRESS")
+            .context("DBUS_SESSION_BUS_ADDRESS is not set")
+            .and_then(|address| DbusService::start(self.config, &address));
+        match started {
+            Ok(service) => Some(service),
+            Err(e) => {
+                warn!("D-Bus service disabled: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Uses the listener passed by systemd socket activation, otherwise binds `path` itself.
+    ///
+    /// Either way the instance lock is taken, so hosts see the server as running; the returned
+    /// guard holds it and removes the socket file on drop unless systemd owns it. When binding,
+    /// the lock comes first: a socket file found while holding it is stale and replaced, unless
+    /// a server without the lock (an older version) still answers on it. With an allowed group
+    /// the socket is made group-accessible (0660, owned by that group).
+    fn bind_socket(path: &Path, group: Option<u32>) -> Result<(UnixListener, SocketGuard)> {
+        if let Some(listener) = systemd::take_listener() {
+            // Lock the path systemd listens on, which hosts derive the lock file from.
+            let activated = listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(Path::to_path_buf))
+                .unwrap_or_else(|| path.to_path_buf());
+            let guard = SocketGuard {
+                path: None,
+                _lock: InstanceLock::acquire(&activated)?,
+            };
+            return Ok((listener, guard));
+        }
+        let lock = InstanceLock::acquire(path)?;
+        if path.exists() {
+            if let Ok(stream) = UnixStream::connect(path) {
+                match PeerCred::of(&stream) {
+                    Ok(peer) => bail!("walrusfox server is already running (pid {})", peer.pid),
+                    Err(_) => bail!("walrusfox server is already running"),
+                }
+            }
+            info!("Removing stale socket {}", path.display());
+            remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+        }
+        let listener =
+            UnixListener::bind(path).with_context(|| format!("bind {}", path.display()))?;
+        let guard = SocketGuard {
+            path: Some(path.to_path_buf()),
+            _lock: lock,
+        };
+        let mode = if group.is_some() { 0o660 } else { 0o600 };
+        if let Err(e) = set_permissions(path, Permissions::from_mode(mode)) {
+            warn!("Failed to set socket permissions to {:o}: {}", mode, e);
+        }
+        if let Some(gid) = group {
+            if let Err(e) = std::os::unix::fs::chown(path, None, Some(gid)) {
+                warn!("Failed to hand the socket to group {}: {}", gid, e);
+            }
+            if let Some(dir) = peer::unreachable_dir(path, gid) {
+                warn!(
+                    "Group {} cannot reach the socket: {} is not searchable for it; set WALRUSFOX_SOCKET to a shared directory",
+                    gid,
+                    dir.display()
+                );
+            }
+        }
+        Ok((listener, guard))
+    }
+}
+
+/// Everything the event loop owns: connections, pending acknowledgements and the theme.
+struct Hub {
+    clients: HashMap<u64, Client>,
+    next_client: u64,
+    acks: Acks,
+    debounce: Debouncer,
+    theme: ThemeState,
+    notifier: Option<Notifier>,
+    colors_file: PathBuf,
+    state_file: Option<PathBuf>,
+    policy: PeerPolicy,
+    rate: u32,
+    started_at: u64,
+    evicted: u64,
+    next_heartbeat: Instant,
+    heartbeats: u64,
+}
+
+impl Hub {
+    fn new(config: &Config) -> Self {
+        let group = config.settings.allowed_group.as_deref().and_then(|name| {
+            let gid = PeerPolicy::resolve_group(name);
+            if gid.is_none() {
+                warn!(
+                    "Unknown allowedGroup {:?}; only our own user may connect",
+                    name
+                );
+            }
+            gid
+        });
+        Self {
+            clients: HashMap::new(),
+            next_client: 0,
+            acks: Acks::default(),
+            debounce: Debouncer::new(config.settings.debounce()),
+            theme: config
+                .state_file
+                .as_deref()
+                .map(ThemeState::load)
+                .unwrap_or_default(),
+            notifier: Notifier::from_env(),
+            colors_file: config.colors_file.clone(),
+            state_file: config.state_file.clone(),
+            policy: PeerPolicy::new(group),
+            rate: config.settings.commands_per_second(),
+            started_at: unix_now(),
+            evicted: 0,
+            next_heartbeat: Instant::now() + HEARTBEAT_INTERVAL,
+            heartbeats: 0,
+        }
+    }
+
+    /// Waits up to `timeout` (forever with `None`) and serves whatever became ready.
+    fn poll(
+        &mut self,
+        listener: &UnixListener,
+        wake: &UnixStream,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        let pollfd = |fd: &dyn AsRawFd, events| libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events,
+            revents: 0,
+        };
+        let ids: Vec<u64> = self.clients.keys().copied().collect();
+        let mut fds = vec![pollfd(listener, libc::POLLIN), pollfd(wake, libc::POLLIN)];
+        for id in &ids {
+            let client = &self.clients[id];
+            let mut events = libc::POLLIN;
+            if !client.output.is_empty() {
+                events |= libc::POLLOUT;
+            }
+            fds.push(pollfd(&client.stream, events));
+        }
+        // Round up so a deadline less than a millisecond away does not spin.
+        let timeout_ms = timeout.map_or(-1, |t| {
+            t.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32
+        });
+        // SAFETY: `fds` is a valid array of `fds.len()` pollfds for the duration of the call.
+        let n = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                ErrorKind::Interrupted => Ok(()),
+                _ => Err(err),
+            };
+        }
+
+        if fds[1].revents != 0 {
+            let mut buf = [0u8; 64];
+            while matches!((&*wake).read(&mut buf), Ok(n) if n > 0) {}
+        }
+        if fds[0].revents & libc::POLLIN != 0 {
+            self.accept(listener);
+        }
+        for (id, fd) in ids.iter().zip(&fds[2..]) {
+            if fd.revents & libc::POLLOUT != 0 {
+                if let Some(client) = self.clients.get_mut(id) {
+                    client.flush();
+                }
+            }
+            if fd.revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0 {
+                self.read(*id);
+            }
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self, listener: &UnixListener) {
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    debug!("Error accepting connection: {}", e);
+                    return;
+                }
+            };
+            let cred = match PeerCred::of(&stream) {
+                Ok(cred) => cred,
+                Err(e) => {
+                    warn!("Rejected connection: cannot read peer credentials: {}", e);
+                    continue;
+                }
+            };
+            if !self.policy.allows(&cred) {
+                warn!(
+                    "Rejected connection from pid {} (uid {}, gid {}): not our user or allowed group",
+                    cred.pid, cred.uid, cred.gid
+                );
+                continue;
+            }
+            if let Err(e) = stream.set_nonblocking(true) {
+                warn!("Rejected connection: cannot make it non-blocking: {}", e);
+                continue;
+            }
+            if self.clients.len() >= MAX_CLIENTS {
+                warn!(
+                    "Rejected connection from pid {}: {} clients already connected",
+                    cred.pid, MAX_CLIENTS
+                );
+                // Best effort: the stream is non-blocking, so a peer that never reads cannot
+                // stall the loop.
+                let reply = Envelope::result(None, &Reply::error("too many clients"));
+                let _ = writeln!(&stream, "{}", reply.to_line());
+                continue;
+            }
+            let id = self.next_client;
+            self.next_client += 1;
+            info!(
+                "Client {} connected (pid {}, uid {})",
+                id, cred.pid, cred.uid
+            );
+            self.clients
+                .insert(id, Client::new(id, stream, cred, self.rate));
+            self.report_status();
+        }
+    }
+
+    fn read(&mut self, client_id: u64) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        if client.closed {
+            return;
+        }
+        let (lines, eof) = client.read_lines();
+        for line in lines {
+            if line.len() > MAX_MSG_LEN {
+                warn!(
+                    "Ignoring overlong command from client {} ({} bytes)",
+                    client_id,
+                    line.len()
+                );
+                continue;
+            }
+            self.handle_line(client_id, &line);
+        }
+        // Answers to the last lines are still written; the client is dropped after that.
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.closed |= eof;
+        }
+    }
+
+    fn handle_line(&mut self, client_id: u64, line: &str) {
+        match Self::route(client_id, line) {
+            Route::Command(envelope, command) => self.command(client_id, envelope, command),
+            Route::Hello(id, hello) => {
+                self.register(client_id, hello);
+                if id.is_some() {
+                    let reply = Envelope::result(id, &Reply::ok(Default::default()));
+                    self.send(client_id, &reply.to_line());
+                }
+            }
+            Route::Query(id, query) => {
+                let reply = self.answer(client_id, query);
+                self.send(client_id, &Envelope::result(id, &reply).to_line());
+            }
+            Route::Ack(key, reply) => {
+                if !reply.ok {
+                    self.record_error(client_id);
+                }
+                let done = self.acks.record(client_id, &key, reply);
+                self.deliver(done);
+            }
+            Route::Ping(ping) => self.send(client_id, &ping.pong().to_line()),
+            Route::Pong(id) => self.heartbeat_answered(client_id, id),
+            Route::Reject(reply) => {
+                self.record_error(client_id);
+                self.send(client_id, &reply.to_line());
+            }
+            Route::Drop => {}
+        }
+    }
+
+    /// Validates a line (legacy word or JSON envelope) and decides what to do with it.
+    fn route(client_id: u64, line: &str) -> Route {
+        let envelope = match Envelope::parse_line(line) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("Rejecting message from client {}: {:#}", client_id, e);
+                let id = serde_json::from_str::<serde_json::Value>(line)
+                    .ok()
+                    .and_then(|v| v.get("id")?.as_str().map(String::from));
+                return Route::Reject(Envelope::result(id, &Reply::error(format!("{e:#}"))));
+            }
+        };
+        let reject = |e: anyhow::Error| {
+            warn!("Rejecting message from client {}: {:#}", client_id, e);
+            Route::Reject(envelope.reply(&Reply::error(format!("{e:#}"))))
+        };
+        match (envelope.kind, envelope.id.clone()) {
+            (MessageType::Hello, id) => match envelope.as_hello() {
+                Ok(hello) => Route::Hello(id, hello),
+                Err(e) => reject(e),
+            },
+            (MessageType::Query, id) => match envelope.as_query() {
+                Ok(query) => Route::Query(id, query),
+                Err(e) => reject(e),
+            },
+            (MessageType::Command, _) => match envelope.as_command() {
+                Ok(command) => Route::Command(envelope, command),
+                Err(e) => reject(e),
+            },
+            (MessageType::Result, Some(key)) => match envelope.as_reply() {
+                Ok(reply) => Route::Ack(key, reply),
+                Err(e) => {
+                    warn!(
+                        "Dropping malformed result from client {}: {:#}",
+                        client_id, e
+                    );
+                    Route::Drop
+                }
+            },
+            (MessageType::Ping, _) => Route::Ping(envelope),
+            (MessageType::Pong, id) => Route::Pong(id),
+            (MessageType::Result, None) | (MessageType::Event, _) | (MessageType::Goodbye, _) => {
+                Route::Drop
+            }
+        }
+    }
+
+    /// Rate-limits a client's command and passes it through the debouncer.
+    fn command(&mut self, client_id: u64, envelope: Envelope, command: Command) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        if !client.bucket.take(Instant::now()) {
+            warn!("Client {} exceeded the command rate limit", client_id);
+            client.record_error();
+            client.send(
+                &envelope
+                    .reply(&Reply::error("rate limit exceeded"))
+                    .to_line(),
+            );
+            return;
+        }
+        client.record_command(&command);
+        let batch = Batch {
+            command,
+            target: envelope.target,
+            senders: vec![client_id],
+            waiters: envelope.id.map(|id| (client_id, id)).into_iter().collect(),
+        };
+        for batch in self.debounce.submit(batch, Instant::now()) {
+            self.dispatch(batch);
+        }
+    }
+
+    /// Sends a command to every host; with waiters, their results are collected for them.
+    fn dispatch(&mut self, batch: Batch) {
+        let targets: Vec<u64> = self
+            .clients
+            .iter()
+            .filter(|(id, c)| !batch.senders.contains(id) && c.is_host() && !c.closed)
+            .map(|(id, _)| *id)
+            .collect();
+        if batch.target.is_none() {
+            self.record_theme(&batch.command);
+        }
+        self.emit(&Event::Command {
+            command: batch.command.clone(),
+            target: batch.target.clone(),
+        });
+        let mut envelope = Envelope::command(&batch.command).with_target(batch.target);
+        if !batch.waiters.is_empty() {
+            let deadline = Instant::now() + ACK_TIMEOUT;
+            match self
+                .acks
+                .start(batch.waiters, targets.iter().copied(), deadline)
+            {
+                Ok(key) => envelope = envelope.with_id(key),
+                Err(done) => {
+                    self.deliver(done);
+                    return;
+                }
+            }
+        }
+        let line = envelope.to_line();
+        for id in targets {
+            if let Some(client) = self.clients.get_mut(&id) {
+                client.record_command(&batch.command);
+                client.send(&line);
+            }
+        }
+    }
+
+    fn register(&mut self, client_id: u64, hello: Hello) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        let role = hello.role;
+        client.info.role = hello.role;
+        client.info.browser = hello.browser;
+        client.info.profile = hello.profile;
+        client.info.subscribed = hello.subscribe;
+        client.registered = true;
+        let host = client.info.label();
+        info!("Client {} registered as {:?}: {}", client_id, role, host);
+        if role == Role::Host {
+            // Bring the new browser in line with the others.
+            for command in self.theme.replay() {
+                client.send(&Envelope::command(&command).to_line());
+            }
+            self.emit(&Event::HostConnected { host });
+        }
+    }
+
+    fn record_theme(&mut self, command: &Command) {
+        self.theme.record(command, &self.colors_file);
+        if let Some(path) = &self.state_file {
+            if let Err(e) = self.theme.save(path) {
+                warn!("Failed to save theme state: {:#}", e);
+            }
+        }
+    }
+
+    fn answer(&self, client_id: u64, query: Query) -> Reply {
+        match query {
+            Query::Clients => {
+                let mut list: Vec<ClientInfo> = self
+                    .clients
+                    .iter()
+                    .filter(|(id, _)| **id != client_id)
+                    .map(|(_, c)| ClientInfo {
+                        queued: c.output.len(),
+                        ..c.info.clone()
+                    })
+                    .collect();
+                list.sort_by_key(|c| c.id);
+                Reply::ok(serde_json::to_value(list).unwrap_or_default())
+            }
+            Query::Status => {
+                let hosts = self.clients.values().filter(|c| c.is_host()).count();
+                let status = ServerStatus {
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    pid: std::process::id(),
+                    started_at: self.started_at,
+                    hosts,
+                    // The asking connection is not counted.
+                    controllers: (self.clients.len() - hosts).saturating_sub(1),
+                    evicted: self.evicted,
+                    theme: self.theme.status.clone(),
+                };
+                Reply::ok(serde_json::to_value(status).unwrap_or_default())
+            }
+            Query::Palette => match self.theme.current_palette(&self.colors_file) {
+                Ok(palette) => Reply::ok(serde_json::to_value(palette).unwrap_or_default()),
+                Err(e) => Reply::error(format!("{e:#}")),
+            },
+        }
+    }
+
+    /// Sends an event to every controller that subscribed to them.
+    fn emit(&mut self, event: &Event) {
+        let line = Envelope::event(event).to_line();
+        for client in self.clients.values_mut() {
+            if client.info.role == Role::Controller && client.info.subscribed {
+                client.send(&line);
+            }
+        }
+    }
+
+    /// Reports hosts that never answered once their deadline passed, relays trailing sends
+    /// whose debounce window ended, evicts clients whose output stalled for too long and sends
+    /// heartbeats.
+    fn run_timers(&mut self, now: Instant) {
+        self.heartbeat(now);
+        for key in self.acks.overdue(now) {
+            if let Some((done, silent)) = self.acks.expire(&key) {
+                for id in silent {
+                    self.record_error(id);
+                }
+                self.deliver(done);
+            }
+        }
+        for batch in self.debounce.due(now) {
+            self.dispatch(batch);
+        }
+        for client in self.clients.values_mut() {
+            if client
+                .stalled_since
+                .is_some_and(|at| at + WRITE_TIMEOUT <= now)
+            {
+                let reason = format!("writes timed out after {}s", WRITE_TIMEOUT.as_secs());
+                client.evict(&reason);
+            }
+        }
+    }
+
+    /// Pings every client that said `hello`; one that left `MAX_MISSED_HEARTBEATS` pings in a
+    /// row unanswered is disconnected, as a frozen peer would otherwise stay forever.
+    fn heartbeat(&mut self, now: Instant) {
+        if now < self.next_heartbeat {
+            return;
+        }
+        self.next_heartbeat = now + HEARTBEAT_INTERVAL;
+        self.heartbeats += 1;
+        let id = format!("heartbeat-{}", self.heartbeats);
+        let line = Envelope::ping(id.clone()).to_line();
+        for client in self.clients.values_mut() {
+            if !client.registered || client.closed {
+                continue;
+            }
+            if client.heartbeat.is_some() {
+                client.missed_heartbeats += 1;
+            }
+            if client.missed_heartbeats >= MAX_MISSED_HEARTBEATS {
+                warn!(
+                    "Client {} ({}) missed {} heartbeats; disconnecting",
+                    client.info.id,
+                    client.info.label(),
+                    client.missed_heartbeats
+                );
+                client.closed = true;
+                continue;
+            }
+            client.heartbeat = Some((id.clone(), now));
+            client.send(&line);
+        }
+    }
+
+    fn heartbeat_answered(&mut self, client_id: u64, id: Option<String>) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        match &client.heartbeat {
+            Some((sent_id, sent_at)) if id.as_ref() == Some(sent_id) => {
+                client.info.rtt_ms = Some(sent_at.elapsed().as_secs_f64() * 1000.0);
+                client.heartbeat = None;
+                client.missed_heartbeats = 0;
+            }
+            _ => debug!("Ignoring stale pong from client {}", client_id),
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let stalled = self
+            .clients
+            .values()
+            .filter_map(|c| c.stalled_since)
+            .map(|at| at + WRITE_TIMEOUT);
+        [
+            self.acks.next_deadline(),
+            self.debounce.next_due(),
+            Some(self.next_heartbeat),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(stalled)
+        .min()
+    }
+
+    /// Drops closed connections. Settling their acknowledgements writes to other clients,
+    /// which may close those too, so this repeats until none are left.
+    fn reap(&mut self) {
+        loop {
+            let closed: Vec<u64> = self
+                .clients
+                .iter()
+                .filter(|(_, c)| c.closed)
+                .map(|(id, _)| *id)
+                .collect();
+            if closed.is_empty() {
+                return;
+            }
+            for id in closed {
+                let Some(mut client) = self.clients.remove(&id) else {
+                    continue;
+                };
+                client.flush();
+                info!("Client {} disconnected", id);
+                if client.evicted {
+                    self.evicted += 1;
+                }
+                if client.is_host() {
+                    let host = client.info.label();
+                    self.emit(&Event::HostDisconnected { host });
+                }
+                let done = self.acks.drop_client(id);
+                self.deliver(done);
+            }
+            self.report_status();
+        }
+    }
+
+    /// Relays pending trailing sends, answers everyone still waiting for a result and tells
+    /// every client the server is going away.
+    fn say_goodbye(&mut self) {
+        for batch in self.debounce.flush_all() {
+            self.dispatch(batch);
+        }
+        let done = self.acks.cancel_all("server is shutting down");
+        self.deliver(done);
+        let line = Envelope::goodbye("shutdown").to_line();
+        for client in self.clients.values_mut() {
+            client.send(&line);
+        }
+    }
+
+    /// Writes out the queues until they are empty or `deadline` passes.
+    fn drain(&mut self, deadline: Instant) {
+        loop {
+            let pending: Vec<u64> = self
+                .clients
+                .iter()
+                .filter(|(_, c)| !c.closed && !c.output.is_empty())
+                .map(|(id, _)| *id)
+                .collect();
+            if pending.is_empty() {
+                return;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                warn!(
+                    "Gave up flushing output to {} client(s) on shutdown",
+                    pending.len()
+                );
+                return;
+            }
+            let mut fds: Vec<libc::pollfd> = pending
+                .iter()
+                .map(|id| libc::pollfd {
+                    fd: self.clients[id].stream.as_raw_fd(),
+                    events: libc::POLLOUT,
+                    revents: 0,
+                })
+                .collect();
+            let timeout_ms = (deadline - now).as_millis().max(1) as i32;
+            // SAFETY: `fds` is a valid array of `fds.len()` pollfds for the duration of the call.
+            unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+            for (id, fd) in pending.iter().zip(&fds) {
+                if fd.revents != 0 {
+                    if let Some(client) = self.clients.get_mut(id) {
+                        client.flush();
+                        // A peer that hung up will not read the rest.
+                        if fd.revents & (libc::POLLHUP | libc::POLLERR) != 0 {
+                            client.closed = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Flushes what the sockets take without blocking and closes every connection.
+    fn close_all(&mut self) {
+        for (_, mut client) in self.clients.drain() {
+            client.flush();
+        }
+    }
+
+    fn deliver(&mut self, done: Vec<Delivery>) {
+        for (origin, envelope) in done {
+            self.send(origin, &envelope.to_line());
+        }
+    }
+
+    fn send(&mut self, client_id: u64, line: &str) {
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.send(line);
+        }
+    }
+
+    fn record_error(&mut self, client_id: u64) {
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.record_error();
+        }
+    }
+
+    fn report_status(&self) {
+        if let Some(notifier) = &self.notifier {
+            notifier.status(&format!(
+                "Listening; {} client(s) connected",
+                self.clients.len()
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::thread;
+
+    fn read_envelope(reader: &mut BufReader<UnixStream>) -> Option<Envelope> {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        Envelope::parse_line(&line).ok()
+    }
+
+    #[test]
+    fn evicts_clients_that_stop_reading() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let cred = PeerCred::of(&stream).unwrap();
+        let mut client = Client::new(0, stream, cred, 0);
+        let line = "x".repeat(64 * 1024);
+        for _ in 0..(MAX_QUEUED_BYTES / line.len() + 16) {
+            client.send(&line);
+        }
+        assert!(client.closed && client.evicted);
+        assert!(client.output.is_empty());
+    }
+
+    #[test]
+    fn reaps_clients_that_miss_heartbeats() {
+        let mut hub = Hub::new(&Config::default());
+        // The other ends stay open until the test ends, so pings are delivered, not refused.
+        let mut peers = Vec::new();
+        for id in 0..2 {
+            let (stream, peer) = UnixStream::pair().unwrap();
+            stream.set_nonblocking(true).unwrap();
+            let cred = PeerCred::of(&stream).unwrap();
+            let mut client = Client::new(id, stream, cred, 0);
+            client.registered = true;
+            hub.clients.insert(id, client);
+            peers.push(peer);
+        }
+
+        let mut now = Instant::now();
+        for _ in 0..=MAX_MISSED_HEARTBEATS {
+            now += HEARTBEAT_INTERVAL;
+            hub.heartbeat(now);
+            // Client 0 answers every ping, client 1 none.
+            let id = hub.clients[&0].heartbeat.as_ref().map(|(id, _)| id.clone());
+            hub.heartbeat_answered(0, id);
+        }
+        assert!(!hub.clients[&0].closed);
+        assert!(hub.clients[&0].info.rtt_ms.is_some());
+        assert!(hub.clients[&1].closed);
+        drop(peers);
+    }
+
+    #[test]
+    fn relays_commands_and_stops_cleanly() {
+        let dir = std::env::temp_dir().join(format!("walrusfox-server-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            socket_file: dir.join("walrusfox.sock"),
+            ..Config::default()
+        };
+        let shutdown = Shutdown::new();
+        let server = Server::new(&config).with_shutdown(shutdown.clone());
+        thread::scope(|scope| {
+            let running = scope.spawn(|| server.init());
+            while UnixStream::connect(&config.socket_file).is_err() {
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            let mut host = UnixStream::connect(&config.socket_file).unwrap();
+            let hello = Hello {
+                role: Role::Host,
+                ..Hello::default()
+            };
+            writeln!(host, "{}", Envelope::hello(&hello).with_id("h").to_line()).unwrap();
+            let mut host_reader = BufReader::new(host.try_clone().unwrap());
+            let registered = read_envelope(&mut host_reader).unwrap();
+            assert!(registered.as_reply().unwrap().ok);
+
+            let mut controller = UnixStream::connect(&config.socket_file).unwrap();
+            let update = Envelope::command(&Command::Update { palette: None }).with_id("1");
+            writeln!(controller, "{}", update.to_line()).unwrap();
+            let relayed = read_envelope(&mut host_reader).unwrap();
+            assert_eq!(
+                relayed.as_command().unwrap(),
+                Command::Update { palette: None }
+            );
+            let ack = relayed.reply(&Reply::ok(Default::default()));
+            writeln!(host, "{}", ack.to_line()).unwrap();
+            let result = read_envelope(&mut BufReader::new(controller)).unwrap();
+            assert_eq!(result.id.as_deref(), Some("1"));
+            assert!(result.as_reply().unwrap().ok);
+
+            shutdown.trigger();
+            running.join().unwrap().unwrap();
+            let goodbye = read_envelope(&mut host_reader).unwrap();
+            assert_eq!(goodbye.kind, MessageType::Goodbye);
+            assert!(read_envelope(&mut host_reader).is_none());
+        });
+        assert!(!config.socket_file.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,179 @@
+use std::ffi::CString;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// Credentials of the process on the other end of a Unix socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl PeerCred {
+    /// Reads `SO_PEERCRED`; the kernel records these at `connect()` time.
+    pub fn of(stream: &UnixStream) -> io::Result<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: `cred` and `len` are valid for writes and sized for `ucred`.
+        let rc = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                (&mut cred as *mut libc::ucred).cast(),
+                &mut len,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            pid: cred.pid as u32,
+            uid: cred.uid,
+            gid: cred.gid,
+        })
+    }
+}
+
+/// Who may talk to the server: our own user, plus members of an optional group.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerPolicy {
+    uid: u32,
+    group: Option<u32>,
+}
+
+impl PeerPolicy {
+    pub fn new(group: Option<u32>) -> Self {
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        let uid = unsafe { libc::geteuid() };
+        Self { uid, group }
+    }
+
+    /// Resolves a group name or numeric gid.
+    pub fn resolve_group(name: &str) -> Option<u32> {
+        if let Ok(gid) = name.parse() {
+            return Some(gid);
+        }
+        let cname = CString::new(name).ok()?;
+        // SAFETY: `cname` is a valid C string; the returned record is only read before the next
+        // call into the group database.
+        let group = unsafe { libc::getgrnam(cname.as_ptr()) };
+        if group.is_null() {
+            return None;
+        }
+        Some(unsafe { (*group).gr_gid })
+    }
+
+    pub fn group(&self) -> Option<u32> {
+        self.group
+    }
+
+    pub fn allows(&self, peer: &PeerCred) -> bool {
+        if peer.uid == self.uid {
+            return true;
+        }
+        let Some(group) = self.group else {
+            return false;
+        };
+        peer.gid == group || member_groups(peer.uid).contains(&group)
+    }
+}
+
+/// The first directory above `socket` that members of `gid` cannot search, i.e. the reason they
+/// could not connect despite the socket's group permissions.
+pub fn unreachable_dir(socket: &Path, gid: u32) -> Option<PathBuf> {
+    socket.ancestors().skip(1).find_map(|dir| {
+        let meta = dir.metadata().ok()?;
+        let search = if meta.gid() == gid { 0o010 } else { 0o001 };
+        (meta.mode() & search == 0).then(|| dir.to_path_buf())
+    })
+}
+
+/// Groups the user `uid` belongs to according to the group database (`getgrouplist(3)`).
+///
+/// Unlike the peer's pid, which may be reused by the time we look, the uid from `SO_PEERCRED`
+/// stays meaningful after the fact.
+fn member_groups(uid: u32) -> Vec<u32> {
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    // SAFETY: all-zero is a valid `passwd` (null pointers); getpwuid_r fills it in.
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    // SAFETY: the buffers are valid for writes of the given sizes; `pwd` points into `buf`.
+    let rc = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if rc != 0 || found.is_null() {
+        return Vec::new();
+    }
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        // SAFETY: `pw_name` is a valid C string in `buf`; `groups` holds `count` entries.
+        let rc =
+            unsafe { libc::getgrouplist(pwd.pw_name, pwd.pw_gid, groups.as_mut_ptr(), &mut count) };
+        if rc >= 0 {
+            groups.truncate(count as usize);
+            return groups;
+        }
+        // Too small; `count` now says how many there are.
+        let needed = (count as usize).max(groups.len() * 2);
+        if needed > 64 * 1024 {
+            return Vec::new();
+        }
+        groups.resize(needed, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_allows_own_uid_and_group_members() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let own = PeerCred::of(&a).unwrap();
+        assert_eq!(own.pid, std::process::id());
+        let stranger = PeerCred {
+            pid: u32::MAX,
+            uid: own.uid + 1,
+            gid: 4242,
+        };
+        assert!(PeerPolicy::new(None).allows(&own));
+        assert!(!PeerPolicy::new(None).allows(&stranger));
+        assert!(PeerPolicy::new(Some(4242)).allows(&stranger));
+        assert!(!PeerPolicy::new(Some(4343)).allows(&stranger));
+        assert_eq!(PeerPolicy::resolve_group("4242"), Some(4242));
+        assert_eq!(PeerPolicy::resolve_group("no-such-group-walrusfox"), None);
+    }
+
+    #[test]
+    fn looks_up_group_membership_by_uid() {
+        // SAFETY: getuid/getgid have no preconditions and cannot fail.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let groups = member_groups(uid);
+        if !groups.is_empty() {
+            assert!(groups.contains(&gid));
+        }
+        assert!(member_groups(u32::MAX - 1).is_empty());
+    }
+
+    #[test]
+    fn finds_the_directory_that_blocks_a_group() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("walrusfox-peer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gid = std::fs::metadata(&dir).unwrap().gid();
+        let socket = dir.join("walrusfox.sock");
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        assert_eq!(unreachable_dir(&socket, gid), Some(dir.clone()));
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o710)).unwrap();
+        assert_eq!(unreachable_dir(&socket, gid), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Asks a running server to shut down gracefully; cheap to clone and safe to trigger from any
+/// thread, including a signal handler thread.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: AtomicBool,
+    /// Write end of the pair the event loop polls, so a request wakes it up immediately.
+    waker: Mutex<Option<UnixStream>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the shutdown; returns false if it had already been requested.
+    pub fn trigger(&self) -> bool {
+        let first = !self.inner.requested.swap(true, Ordering::SeqCst);
+        if let Some(waker) = self.inner.waker.lock().as_mut() {
+            let _ = waker.write(&[1]);
+        }
+        first
+    }
+
+    /// Triggers on SIGINT or SIGTERM; a second signal exits right away. Only for processes that
+    /// own the server: the embedded server must leave the browser host's signals alone.
+    pub fn on_signals(&self) -> Result<()> {
+        let shutdown = self.clone();
+        ctrlc::set_handler(move || {
+            if !shutdown.trigger() {
+                std::process::exit(1);
+            }
+        })
+        .context("installing the signal handler")
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Installs the event loop's waker; a request made before this is seen by the loop's first
+    /// check of `is_triggered`.
+    pub(super) fn arm(&self, waker: UnixStream) {
+        *self.inner.waker.lock() = Some(waker);
+    }
+}
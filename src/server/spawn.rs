@@ -0,0 +1,44 @@
+use anyhow::{Context, Result};
+use std::env;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Argument that makes `walrusfox-ext` run the server instead of bridging a browser. Firefox
+/// always passes the manifest path first, so it never collides with a real invocation.
+pub const SERVE_ARG: &str = "--serve";
+
+/// Re-runs the current `walrusfox-ext` as a server (`--serve`) in its own session, so it outlives
+/// the browser host that started it. It logs to the same file as we do. Returns its pid.
+///
+/// Using our own binary keeps this working wherever the host is installed, e.g. alone in
+/// `<prefix>/libexec/walrusfox/`. Racing callers are harmless: the instance lock lets one server
+/// win and the others exit.
+pub fn spawn_detached() -> Result<u32> {
+    let exe = env::current_exe().context("resolving current executable")?;
+    let mut command = Command::new(&exe);
+    command
+        .arg(SERVE_ARG)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // SAFETY: setsid is async-signal-safe and the closure touches no other state.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("spawning {}", exe.display()))?;
+    let pid = child.id();
+    // Reap it should it exit while we still run, e.g. after losing the instance lock.
+    let _ = thread::Builder::new()
+        .name("walrusfox-server-reaper".to_string())
+        .spawn(move || child.wait());
+    Ok(pid)
+}
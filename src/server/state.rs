@@ -0,0 +1,163 @@
+use crate::protocol::socket::{Command, Palette, PaletteStatus, ThemeStatus};
+use crate::utils::themes;
+use crate::utils::time::unix_now;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+
+/// The theme the server last relayed to all hosts, persisted so it survives restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThemeState {
+    pub status: ThemeStatus,
+    /// The last inline palette; `None` when the colors file is in effect.
+    pub palette: Option<Palette>,
+}
+
+impl ThemeState {
+    /// A missing or unreadable state file starts from the defaults.
+    pub fn load(path: &Path) -> Self {
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring invalid state file {}: {}", path.display(), e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Writes through a temporary file so a crash never leaves a truncated state behind.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(&tmp, data).with_context(|| format!("writing {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("replacing {}", path.display()))
+    }
+
+    /// Commands that bring a newly connected host up to date; empty until something was set.
+    pub fn replay(&self) -> Vec<Command> {
+        if self.status.updated_at.is_none() {
+            return Vec::new();
+        }
+        let mut commands = vec![Command::Mode {
+            mode: self.status.mode,
+        }];
+        if let Some(palette) = &self.palette {
+            commands.push(Command::Update {
+                palette: Some(palette.clone()),
+            });
+        } else if self.status.palette.is_some() {
+            commands.push(Command::Update { palette: None });
+        }
+        commands
+    }
+
+    /// The palette hosts were last told to apply: the inline one, otherwise the colors file as
+    /// it reads now.
+    pub fn current_palette(&self, colors_file: &Path) -> Result<Palette> {
+        if let Some(palette) = &self.palette {
+            return Ok(palette.clone());
+        }
+        let (colors, wallpaper) = themes::read_colors(colors_file)?;
+        Ok(Palette { colors, wallpaper })
+    }
+
+    /// Applies a command sent to every host; targeted commands leave the state alone.
+    pub fn record(&mut self, command: &Command, colors_file: &Path) {
+        match command {
+            Command::Mode { mode } => self.status.mode = *mode,
+            Command::Update {
+                palette: Some(palette),
+            } => {
+                self.status.palette = Some(PaletteStatus {
+                    hash: Some(themes::palette_hash(
+                        &palette.colors,
+                        palette.wallpaper.as_deref(),
+                    )),
+                    source: "inline".into(),
+                    colors: palette.colors.len(),
+                    wallpaper: palette.wallpaper.clone(),
+                });
+                self.palette = Some(palette.clone());
+            }
+            Command::Update { palette: None } => {
+                let read = themes::read_colors(colors_file).ok();
+                self.status.palette = Some(PaletteStatus {
+                    hash: read
+                        .as_ref()
+                        .map(|(colors, wall)| themes::palette_hash(colors, wall.as_deref())),
+                    source: colors_file.display().to_string(),
+                    colors: read.as_ref().map_or(0, |(colors, _)| colors.len()),
+                    wallpaper: read.and_then(|(_, wall)| wall),
+                });
+                self.palette = None;
+            }
+            Command::Css { .. } => return,
+        }
+        self.status.updated_at = Some(unix_now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::socket::ThemeMode;
+
+    #[test]
+    fn records_persists_and_replays() {
+        let mut state = ThemeState::default();
+        assert_eq!(state.status.mode, ThemeMode::Auto);
+        assert!(state.status.updated_at.is_none());
+        assert!(state.replay().is_empty());
+
+        let missing = Path::new("/nonexistent/walrusfox.json");
+        state.record(
+            &Command::Mode {
+                mode: ThemeMode::Dark,
+            },
+            missing,
+        );
+        let palette = Palette {
+            colors: vec!["#000000".into(), "#ffffff".into()],
+            wallpaper: None,
+        };
+        state.record(
+            &Command::Update {
+                palette: Some(palette.clone()),
+            },
+            missing,
+        );
+        assert_eq!(state.status.mode, ThemeMode::Dark);
+        let status = state.status.palette.clone().unwrap();
+        assert_eq!(status.source, "inline");
+        assert_eq!(status.colors, 2);
+        assert_eq!(state.palette, Some(palette.clone()));
+
+        let dir = std::env::temp_dir().join(format!("walrusfox-state-{}", std::process::id()));
+        let file = dir.join("state.json");
+        state.save(&file).unwrap();
+        let loaded = ThemeState::load(&file);
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(loaded.replay(), state.replay());
+        assert_eq!(
+            loaded.replay(),
+            vec![
+                Command::Mode {
+                    mode: ThemeMode::Dark
+                },
+                Command::Update {
+                    palette: Some(palette)
+                }
+            ]
+        );
+
+        state.record(&Command::Update { palette: None }, missing);
+        assert_eq!(state.status.palette.unwrap().hash, None);
+        assert!(state.palette.is_none());
+    }
+}
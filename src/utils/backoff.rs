@@ -0,0 +1,73 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Exponential backoff with jitter: each delay doubles up to `max`, and a random share of up to
+/// half of it is taken off, so bridges that lost the server together do not retry in lockstep.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+    seed: u64,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        let seed = (u64::from(std::process::id()) << 32) ^ u64::from(nanos);
+        Self::with_seed(base, max, seed)
+    }
+
+    fn with_seed(base: Duration, max: Duration, seed: u64) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+            // xorshift never leaves zero.
+            seed: seed.max(1),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Delays handed out since the last reset.
+    pub fn attempts(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        delay.mul_f64(1.0 - (self.seed % 1000) as f64 / 2000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_with_jitter_up_to_max() {
+        let ms = Duration::from_millis;
+        let mut backoff = Backoff::with_seed(ms(100), ms(1000), 42);
+        for ceiling in [100, 200, 400, 800, 1000, 1000] {
+            let delay = backoff.next_delay();
+            assert!(
+                delay <= ms(ceiling) && delay >= ms(ceiling / 2),
+                "{delay:?}"
+            );
+        }
+        assert_eq!(backoff.attempts(), 6);
+        backoff.reset();
+        assert!(backoff.next_delay() <= ms(100));
+    }
+}
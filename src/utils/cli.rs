@@ -1,4 +1,5 @@
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -13,24 +14,99 @@ pub struct Cli {
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    /// Install the Firefox native messaging manifest (user scope)
-    Install,
-    /// Uninstall the Firefox native messaging manifest (user scope)
-    Uninstall,
+    /// Install the Firefox native messaging manifest (user scope unless --system)
+    Install {
+        /// Install system-wide under /usr/lib/mozilla (requires root)
+        #[arg(long)]
+        system: bool,
+        /// Installation prefix for --system (default /usr); DESTDIR is honored for staging
+        #[arg(long, requires = "system")]
+        prefix: Option<PathBuf>,
+        /// Copy walrusfox-ext to ~/.local/libexec/walrusfox and point the manifest there
+        #[arg(long, conflicts_with = "system")]
+        copy: bool,
+        /// Also install and enable systemd user units (socket activation for the server)
+        #[arg(long, conflicts_with = "system")]
+        systemd: bool,
+    },
+    /// Uninstall the Firefox native messaging manifest and systemd units (user scope unless --system)
+    Uninstall {
+        /// Remove a system-wide install
+        #[arg(long)]
+        system: bool,
+        /// Installation prefix used with `install --system`
+        #[arg(long, requires = "system")]
+        prefix: Option<PathBuf>,
+        /// Put the backed-up Python pywalfox manifest back afterwards
+        #[arg(long, conflicts_with = "system")]
+        restore: bool,
+    },
+    /// Migrate an existing Python pywalfox installation (backup, settings, manifest)
+    Migrate,
     /// Start the native host in the foreground (stdin/stdout)
     Start,
     /// Trigger an update (refetch colors)
-    Update,
+    Update {
+        /// Send the palette from this colors file inline instead of the configured one
+        #[arg(long, value_name = "FILE")]
+        palette: Option<PathBuf>,
+        /// Only theme this browser (e.g. `firefox`) or profile name
+        #[arg(long, value_name = "BROWSER")]
+        target: Option<String>,
+    },
     /// Set theme mode to dark
-    Dark,
+    Dark {
+        /// Only switch this browser (e.g. `firefox`) or profile name
+        #[arg(long, value_name = "BROWSER")]
+        target: Option<String>,
+    },
     /// Set theme mode to light
-    Light,
+    Light {
+        /// Only switch this browser (e.g. `firefox`) or profile name
+        #[arg(long, value_name = "BROWSER")]
+        target: Option<String>,
+    },
     /// Set theme mode to auto
-    Auto,
+    Auto {
+        /// Only switch this browser (e.g. `firefox`) or profile name
+        #[arg(long, value_name = "BROWSER")]
+        target: Option<String>,
+    },
+    /// Print server events (hosts connecting, commands relayed) as JSON lines until interrupted
+    Events,
+    /// Show the theme mode and palette in effect and the server state
+    Status {
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the browser hosts (and other clients) connected to the server
+    Clients {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
     /// Check connectivity to the local server
     Health,
     /// Print diagnostics about configuration, socket, and logs
     Diagnose,
+    /// Verify the browser integration end to end (manifests, host binary, conflicts)
+    Doctor,
+    /// Inspect Firefox (and fork) profiles
+    Profiles {
+        #[command(subcommand)]
+        command: ProfilesCommand,
+    },
     /// Print the native messaging manifest JSON to stdout (no file changes)
     PrintManifest,
 }
+
+#[derive(Subcommand, Debug)]
+pub enum ProfilesCommand {
+    /// List profiles per browser from profiles.ini/installs.ini (* marks the default)
+    List {
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+}
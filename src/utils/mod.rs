@@ -1,4 +1,7 @@
+pub mod backoff;
 pub mod cli;
 
 pub mod logging;
+pub mod systemd;
 pub mod themes;
+pub mod time;
@@ -0,0 +1,112 @@
+use std::env;
+use std::os::fd::FromRawFd;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram, UnixListener};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// First file descriptor passed by systemd (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: i32 = 3;
+
+/// Takes the listening socket handed over by systemd socket activation, if it is meant for us.
+///
+/// Follows `sd_listen_fds(3)`: only honored when `LISTEN_PID` is our pid. The variables are left
+/// alone, as other threads may be running by now; a child process sees a different pid and
+/// ignores them, and the descriptor is close-on-exec.
+pub fn take_listener() -> Option<UnixListener> {
+    let pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    let fds: i32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        warn!("systemd passed {} sockets; using only the first", fds);
+    }
+
+    // SAFETY: systemd guarantees fd 3 is open and owned by us when LISTEN_PID matches.
+    unsafe {
+        libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+    let listener = unsafe { UnixListener::from_raw_fd(LISTEN_FDS_START) };
+    match listener.local_addr() {
+        Ok(addr) => {
+            info!("Using socket-activated listener {:?}", addr);
+            Some(listener)
+        }
+        Err(e) => {
+            warn!(
+                "Inherited fd {} is not a Unix socket: {}",
+                LISTEN_FDS_START, e
+            );
+            None
+        }
+    }
+}
+
+/// Speaks the `sd_notify(3)` datagram protocol on `$NOTIFY_SOCKET` without linking libsystemd.
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// `None` unless we run under a systemd unit that expects notifications.
+    pub fn from_env() -> Option<Self> {
+        let path = env::var("NOTIFY_SOCKET").ok()?;
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()).ok()?,
+            None => SocketAddr::from_pathname(&path).ok()?,
+        };
+        let socket = match UnixDatagram::unbound() {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Cannot create notify socket: {}", e);
+                return None;
+            }
+        };
+        Some(Self {
+            socket,
+            addr,
+            watchdog: Self::watchdog_from_env(),
+        })
+    }
+
+    fn watchdog_from_env() -> Option<Duration> {
+        if let Ok(pid) = env::var("WATCHDOG_PID") {
+            if pid.parse::<u32>().ok()? != std::process::id() {
+                return None;
+            }
+        }
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        (usec > 0).then(|| Duration::from_micros(usec))
+    }
+
+    /// How often to send `WATCHDOG=1`: half the configured timeout, as systemd recommends.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    pub fn status(&self, status: &str) {
+        self.send(&format!("STATUS={}", status.replace('\n', " ")));
+    }
+
+    pub fn watchdog(&self) {
+        if self.watchdog.is_some() {
+            self.send("WATCHDOG=1");
+        }
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            debug!("sd_notify {:?} failed: {}", state, e);
+        }
+    }
+}
@@ -1,18 +1,42 @@
 use anyhow::{bail, Context, Result};
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::Path;
 use tracing::{info, warn};
 
 #[derive(Debug, Deserialize)]
 struct ColorFile {
-    colors: Vec<String>,
+    colors: Colors,
     wallpaper: Option<String>,
 }
 
-pub fn read_colors() -> Result<(Vec<String>, Option<String>)> {
-    let path = colors_path();
-    let data = match fs::read_to_string(&path) {
+/// walrusfox/wallust write a plain list; pywal's `colors.json` uses `{"color0": ..., "color15": ...}`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Colors {
+    List(Vec<String>),
+    Named(BTreeMap<String, String>),
+}
+
+impl Colors {
+    fn into_list(self) -> Vec<String> {
+        match self {
+            Colors::List(list) => list,
+            Colors::Named(map) => {
+                let mut indexed: Vec<(usize, String)> = map
+                    .into_iter()
+                    .filter_map(|(k, v)| Some((k.strip_prefix("color")?.parse().ok()?, v)))
+                    .collect();
+                indexed.sort_by_key(|(i, _)| *i);
+                indexed.into_iter().map(|(_, v)| v).collect()
+            }
+        }
+    }
+}
+
+pub fn read_colors(path: &Path) -> Result<(Vec<String>, Option<String>)> {
+    let data = match fs::read_to_string(path) {
         Ok(d) => d,
         Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
             bail!("Color definition not found at {}", path.display());
@@ -20,29 +44,35 @@ pub fn read_colors() -> Result<(Vec<String>, Option<String>)> {
         Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
     };
     let parsed: ColorFile = serde_json::from_str(&data).context("Json parse color definition")?;
+    let colors = parsed.colors.into_list();
 
-    if parsed.colors.len() < 16 {
+    if colors.len() < 16 {
         warn!("Color definition contains fewer than 16 colors");
     }
 
-    info!(
-        "Loaded {} colors from {}",
-        parsed.colors.len(),
-        path.display()
-    );
-    Ok((parsed.colors, parsed.wallpaper))
+    info!("Loaded {} colors from {}", colors.len(), path.display());
+    Ok((colors, parsed.wallpaper))
 }
-fn colors_default_path() -> PathBuf {
-    let home = directories::BaseDirs::new()
-        .expect("xdg base")
-        .home_dir()
-        .to_path_buf();
-    home.join(".cache").join("wal").join("walrusfox.json")
+
+/// Stable 64-bit FNV-1a fingerprint of a palette, as 16 hex digits.
+pub fn palette_hash(colors: &[String], wallpaper: Option<&str>) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let parts = colors.iter().map(String::as_str).chain(wallpaper);
+    for byte in parts.flat_map(|p| p.bytes().chain([0])) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
 }
 
-fn colors_path() -> PathBuf {
-    if let Ok(p) = std::env::var("WALRUSFOX_COLORS") {
-        return PathBuf::from(p);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pywal_named_colors_are_ordered_numerically() {
+        let json = r##"{"color10": "#aa", "color2": "#22", "color0": "#00", "special": "x"}"##;
+        let colors: Colors = serde_json::from_str(json).expect("parse");
+        assert_eq!(colors.into_list(), vec!["#00", "#22", "#aa"]);
     }
-    colors_default_path()
 }
@@ -0,0 +1,34 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds since the Unix epoch; timestamps on the socket use this unit.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Compact age of a timestamp for tables, e.g. `42s ago` or `3h ago`.
+pub fn ago(timestamp: u64) -> String {
+    let secs = unix_now().saturating_sub(timestamp);
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ago_picks_the_largest_unit() {
+        let now = unix_now();
+        assert_eq!(ago(now), "0s ago");
+        assert_eq!(ago(now - 125), "2m ago");
+        assert_eq!(ago(now - 2 * 86400), "2d ago");
+        assert_eq!(ago(now + 10), "0s ago");
+    }
+}
@@ -0,0 +1,128 @@
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Lines, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use walrusfox::config::ALLOWED_EXTENSION;
+use walrusfox::protocol::native_messaging::{read_message_from, write_message_to};
+use walrusfox::protocol::socket::{self, Envelope, MessageType, ThemeMode};
+
+/// A browser host connected to a fake server listening in `dir`.
+struct Host {
+    dir: PathBuf,
+    browser: Child,
+    stream: UnixStream,
+}
+
+impl Host {
+    fn start(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("walrusfox-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("walrusfox.sock");
+        let server = UnixListener::bind(&socket).unwrap();
+        let browser = spawn_host(&dir, &socket);
+        let (stream, _) = server.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let host = Self {
+            dir,
+            browser,
+            stream,
+        };
+        let hello = host.next_line(&mut host.lines());
+        assert_eq!(hello.kind, MessageType::Hello);
+        host
+    }
+
+    fn lines(&self) -> Lines<BufReader<&UnixStream>> {
+        BufReader::new(&self.stream).lines()
+    }
+
+    fn next_line(&self, lines: &mut Lines<BufReader<&UnixStream>>) -> Envelope {
+        Envelope::parse_line(&lines.next().unwrap().unwrap()).unwrap()
+    }
+
+    /// Closes the browser side and returns everything the host wrote to it.
+    fn finish(mut self) -> Vec<u8> {
+        drop(self.browser.stdin.take());
+        let mut output = Vec::new();
+        let mut stdout = self.browser.stdout.take().unwrap();
+        stdout.read_to_end(&mut output).unwrap();
+        assert!(self.browser.wait().unwrap().success());
+        let _ = fs::remove_dir_all(&self.dir);
+        output
+    }
+}
+
+fn spawn_host(dir: &Path, socket: &Path) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_walrusfox-ext"))
+        .arg(dir.join("pywalfox.json"))
+        .arg(ALLOWED_EXTENSION)
+        .env("WALRUSFOX_SOCKET", socket)
+        .env("WALRUSFOX_LOG", dir.join("walrusfox.log"))
+        .env("WALRUSFOX_STATE", dir.join("state.json"))
+        .env("WALRUSFOX_CONFIG", dir.join("config.json"))
+        .env("WALRUSFOX_COLORS", dir.join("colors.json"))
+        .env("WALRUSFOX_NO_SERVER", "1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+#[test]
+fn garbage_from_the_socket_never_reaches_the_browser() {
+    let host = Host::start("bridge-garbage");
+    let mut lines = host.lines();
+    writeln!(&host.stream, "this is not json").unwrap();
+    // A command for another browser is answered without reaching this one, so its result
+    // proves the bridge got past the garbage line.
+    let command = Envelope::command(&socket::Command::Mode {
+        mode: ThemeMode::Dark,
+    })
+    .with_id("after-garbage")
+    .with_target(Some("no-such-browser".to_string()));
+    writeln!(&host.stream, "{}", command.to_line()).unwrap();
+    let result = host.next_line(&mut lines);
+    assert_eq!(result.kind, MessageType::Result);
+    assert_eq!(result.id.as_deref(), Some("after-garbage"));
+    drop(lines);
+
+    let output = host.finish();
+    assert!(output.is_empty(), "the browser was sent {output:?}");
+}
+
+#[test]
+fn answers_theme_mode_from_the_relayed_mode() {
+    let mut host = Host::start("bridge-mode");
+    let mut lines = host.lines();
+    let dark = socket::Command::Mode {
+        mode: ThemeMode::Dark,
+    };
+    writeln!(
+        &host.stream,
+        "{}",
+        Envelope::command(&dark).with_id("1").to_line()
+    )
+    .unwrap();
+    let reply = host.next_line(&mut lines);
+    assert_eq!(reply.kind, MessageType::Result);
+    drop(lines);
+    // The fake server never answers queries, so only the relayed mode can say "dark".
+    let mut stdin = host.browser.stdin.take().unwrap();
+    write_message_to(&mut stdin, &json!({ "action": "theme:mode" })).unwrap();
+    drop(stdin);
+
+    let output = host.finish();
+    let mut frames = output.as_slice();
+    let relayed: Value = read_message_from(&mut frames).unwrap().unwrap();
+    let answered: Value = read_message_from(&mut frames).unwrap().unwrap();
+    assert_eq!(relayed["data"], "dark");
+    assert_eq!(answered["action"], "theme:mode");
+    assert_eq!(answered["data"], "dark");
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use walrusfox::config::ALLOWED_EXTENSION;
+
+/// Stops the detached server even when an assertion fails halfway.
+struct Server(Option<u32>);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0 {
+            // SAFETY: kill has no memory preconditions.
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        }
+    }
+}
+
+fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn server_pid(lock: &Path) -> Option<u32> {
+    fs::read_to_string(lock).ok()?.trim().parse().ok()
+}
+
+#[test]
+fn host_alone_in_libexec_starts_a_detached_server() {
+    let dir = std::env::temp_dir().join(format!("walrusfox-spawn-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let libexec = dir.join("libexec").join("walrusfox");
+    fs::create_dir_all(&libexec).unwrap();
+    // Installed like `install --system` does: no `walrusfox` binary next to the host.
+    let host = libexec.join("walrusfox-ext");
+    fs::copy(env!("CARGO_BIN_EXE_walrusfox-ext"), &host).unwrap();
+    fs::set_permissions(&host, fs::Permissions::from_mode(0o755)).unwrap();
+    let socket = dir.join("walrusfox.sock");
+    let lock = PathBuf::from(format!("{}.lock", socket.display()));
+
+    let mut browser = Command::new(&host)
+        .arg(dir.join("pywalfox.json"))
+        .arg(ALLOWED_EXTENSION)
+        .env("WALRUSFOX_SOCKET", &socket)
+        .env("WALRUSFOX_LOG", dir.join("walrusfox.log"))
+        .env("WALRUSFOX_STATE", dir.join("state.json"))
+        .env("WALRUSFOX_CONFIG", dir.join("config.json"))
+        .env("WALRUSFOX_COLORS", dir.join("colors.json"))
+        .env_remove("WALRUSFOX_NO_SERVER")
+        .env_remove("LISTEN_PID")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    wait_for("the server to listen", || {
+        UnixStream::connect(&socket).is_ok()
+    });
+    wait_for("the server pid", || server_pid(&lock).is_some());
+    let pid = server_pid(&lock).unwrap();
+    let server = Server(Some(pid));
+    assert_ne!(pid, browser.id(), "the server was embedded, not detached");
+    assert_eq!(fs::read_link(format!("/proc/{pid}/exe")).unwrap(), host);
+
+    // The browser closing the port ends the host but not the server.
+    drop(browser.stdin.take());
+    assert!(browser.wait().unwrap().success());
+    assert!(UnixStream::connect(&socket).is_ok());
+
+    drop(server);
+    wait_for("the server to remove its socket", || !socket.exists());
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,67 @@
+use std::fs;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use walrusfox::server::InstanceLock;
+
+fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn socket_activated_server_holds_the_instance_lock() {
+    let dir = std::env::temp_dir().join(format!("walrusfox-activated-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("walrusfox.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    let fd = listener.as_raw_fd();
+
+    // Like systemd: the listener is fd 3 and LISTEN_PID names the process that execs the server.
+    let mut command = Command::new("sh");
+    command
+        .args(["-c", "export LISTEN_PID=$$ LISTEN_FDS=1; exec \"$0\" start"])
+        .arg(env!("CARGO_BIN_EXE_walrusfox"))
+        .env("WALRUSFOX_SOCKET", &socket)
+        .env("WALRUSFOX_LOG", dir.join("walrusfox.log"))
+        .env("WALRUSFOX_STATE", dir.join("state.json"))
+        .env("WALRUSFOX_CONFIG", dir.join("config.json"))
+        .env("WALRUSFOX_COLORS", dir.join("colors.json"))
+        .env_remove("NOTIFY_SOCKET");
+    // SAFETY: dup2 and fcntl are async-signal-safe. dup2 clears close-on-exec on fd 3, except
+    // when the listener already is fd 3.
+    unsafe {
+        command.pre_exec(move || {
+            let rc = match fd {
+                3 => libc::fcntl(3, libc::F_SETFD, 0),
+                _ => libc::dup2(fd, 3),
+            };
+            match rc {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        });
+    }
+    let mut server = command.spawn().unwrap();
+    drop(listener);
+
+    wait_for("the instance lock", || InstanceLock::is_held(&socket));
+    assert!(UnixStream::connect(&socket).is_ok());
+
+    // SAFETY: kill has no memory preconditions.
+    unsafe { libc::kill(server.id() as libc::pid_t, libc::SIGTERM) };
+    assert!(server.wait().unwrap().success());
+    assert!(!InstanceLock::is_held(&socket));
+    assert!(
+        socket.exists(),
+        "the server removed the socket systemd owns"
+    );
+    let _ = fs::remove_dir_all(&dir);
+}